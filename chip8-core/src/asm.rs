@@ -0,0 +1,817 @@
+use crate::cli::parse_number;
+use crate::{Instruction, DEFAULT_LOAD_ADDRESS};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/* Assembler for CHIP-8, SUPER-CHIP and XO-CHIP programs.
+ *
+ * Source uses the same mnemonics as the disassembler, one statement per line:
+ *
+ *     ; comments start with a semicolon
+ *     SPEED equ 3              ; constants, also written SPEED = 3
+ *     start:                   ; labels
+ *         LD V0, SPEED
+ *         LD I, sprite
+ *         DRW V0, V1, 5
+ *         JP start
+ *     sprite:
+ *         DB 0xF0, 0x90, 0x90, 0x90, 0xF0
+ *         DW 0x1234            ; big endian words
+ *         INCLUDE "more.asm"   ; relative to the including file
+ *         ORG 0x300            ; continue at an address, padding with zeros
+ *
+ * Numbers can be decimal, hexadecimal (0x) or binary (0b), and operands can add or
+ * subtract numbers, labels and constants. The names of registers and of I, DT, ST, K,
+ * F, HF, B and R are reserved. The program starts at 0x200 unless the source begins
+ * with an ORG. */
+
+// An error with the file and line it was found on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+// Assemble a source file. Included files are found relative to the file including them.
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    let mut lines = Vec::new();
+    read_source(path, &mut lines, 0, None)?;
+
+    Assembler::new(lines).assemble()
+}
+
+/* Assemble source text. Included files are found relative to the current directory,
+ * and errors are reported against the given file name. */
+pub fn assemble(source: &str, file_name: &str) -> Result<Vec<u8>, AsmError> {
+    let mut lines = Vec::new();
+    add_lines(source, file_name, Path::new("."), &mut lines, 0)?;
+
+    Assembler::new(lines).assemble()
+}
+
+// Includes can't nest deeper than this, which also catches files including themselves
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+struct SourceLine {
+    file: String,
+    number: usize,
+    text: String,
+    // Directory that INCLUDE paths on this line are relative to
+    dir: PathBuf,
+}
+
+impl SourceLine {
+    fn error<S: Into<String>>(&self, message: S) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: self.number,
+            message: message.into(),
+        }
+    }
+}
+
+fn read_source(
+    path: &Path,
+    lines: &mut Vec<SourceLine>,
+    depth: usize,
+    included_from: Option<&SourceLine>,
+) -> Result<(), AsmError> {
+    let source = fs::read_to_string(path).map_err(|error| {
+        let message = format!("couldn't read {}: {}", path.display(), error);
+        match included_from {
+            Some(line) => line.error(message),
+            None => AsmError {
+                file: path.display().to_string(),
+                line: 0,
+                message,
+            },
+        }
+    })?;
+
+    let dir = path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .to_path_buf();
+    add_lines(&source, &path.display().to_string(), &dir, lines, depth)
+}
+
+// Split source into lines, expanding INCLUDE directives in place
+fn add_lines(
+    source: &str,
+    file_name: &str,
+    dir: &Path,
+    lines: &mut Vec<SourceLine>,
+    depth: usize,
+) -> Result<(), AsmError> {
+    for (i, text) in source.lines().enumerate() {
+        let line = SourceLine {
+            file: file_name.to_string(),
+            number: i + 1,
+            text: strip_comment(text).trim().to_string(),
+            dir: dir.to_path_buf(),
+        };
+
+        let (keyword, rest) = split_first_word(&line.text);
+        if !keyword.eq_ignore_ascii_case("include") {
+            lines.push(line);
+            continue;
+        }
+
+        let name = parse_string(rest).ok_or_else(|| line.error("expected a quoted file name"))?;
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(line.error("includes are nested too deeply"));
+        }
+
+        read_source(&line.dir.join(name), lines, depth + 1, Some(&line))?;
+    }
+
+    Ok(())
+}
+
+enum Statement {
+    Empty,
+    Instruction(String, Vec<String>),
+    Db(Vec<String>),
+    Dw(Vec<String>),
+    Org(String),
+    Equ(String, String),
+}
+
+struct Assembler {
+    lines: Vec<SourceLine>,
+    symbols: HashMap<String, i64>,
+}
+
+impl Assembler {
+    fn new(lines: Vec<SourceLine>) -> Assembler {
+        Assembler {
+            lines,
+            symbols: HashMap::new(),
+        }
+    }
+
+    fn assemble(mut self) -> Result<Vec<u8>, AsmError> {
+        let lines = std::mem::take(&mut self.lines);
+        let mut statements = Vec::new();
+
+        // First pass: work out where everything goes and define labels and constants
+        let mut origin = None;
+        let mut address = DEFAULT_LOAD_ADDRESS as i64;
+
+        for line in lines.iter() {
+            let (label, statement) = parse_line(&line.text).map_err(|error| line.error(error))?;
+
+            if let Some(label) = label {
+                self.define(line, &label, address)?;
+            }
+
+            match &statement {
+                Statement::Equ(name, expr) => {
+                    let value = self
+                        .eval(expr, address)
+                        .map_err(|error| line.error(error))?;
+                    self.define(line, name, value)?;
+                }
+                Statement::Org(expr) => {
+                    let target = self
+                        .eval(expr, address)
+                        .map_err(|error| line.error(error))?;
+                    if origin.is_some() && target < address {
+                        return Err(line.error(format!(
+                            "ORG {:#05X} is behind the current address {:#05X}",
+                            target, address
+                        )));
+                    }
+
+                    address = target;
+                }
+                _ => {
+                    let size = statement_size(&statement).map_err(|error| line.error(error))?;
+                    if size > 0 && origin.is_none() {
+                        origin = Some(address);
+                    }
+
+                    address += size;
+                }
+            }
+
+            statements.push((line, address, statement));
+        }
+
+        // Second pass: encode everything now that all labels are known
+        let origin = origin.unwrap_or(address);
+        let mut output = Vec::new();
+
+        for (line, end_address, statement) in statements.iter() {
+            let start = origin + output.len() as i64;
+            let bytes = match statement {
+                Statement::Instruction(mnemonic, operands) => self
+                    .encode_instruction(mnemonic, operands, start)
+                    .map_err(|error| line.error(error))?,
+                Statement::Db(values) => self
+                    .encode_data(values, start, 1)
+                    .map_err(|error| line.error(error))?,
+                Statement::Dw(values) => self
+                    .encode_data(values, start, 2)
+                    .map_err(|error| line.error(error))?,
+                Statement::Org(_) => {
+                    // Pad up to the new address, unless nothing has been output yet
+                    vec![0; (*end_address - start).max(0) as usize]
+                }
+                Statement::Equ(_, _) | Statement::Empty => Vec::new(),
+            };
+
+            output.extend_from_slice(&bytes);
+        }
+
+        Ok(output)
+    }
+
+    fn define(&mut self, line: &SourceLine, name: &str, value: i64) -> Result<(), AsmError> {
+        if !is_identifier(name) || is_reserved(name) {
+            return Err(line.error(format!("'{}' can't be used as a name", name)));
+        }
+
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(line.error(format!("'{}' is already defined", name)));
+        }
+
+        Ok(())
+    }
+
+    // Evaluate sums and differences of numbers and symbols
+    fn eval(&self, expr: &str, address: i64) -> Result<i64, String> {
+        let expr = expr.trim();
+        if expr.is_empty() {
+            return Err("expected a value".to_string());
+        }
+
+        let mut total = 0;
+        let mut sign = 1;
+        let mut rest = expr;
+
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix('-') {
+                sign = -sign;
+                rest = after;
+                continue;
+            }
+
+            let end = rest.find(['+', '-']).unwrap_or(rest.len());
+            let term = rest[..end].trim();
+            total += sign * self.eval_term(term, address)?;
+
+            rest = &rest[end..];
+            match rest.chars().next() {
+                Some('+') => sign = 1,
+                Some('-') => sign = -1,
+                _ => return Ok(total),
+            }
+            rest = &rest[1..];
+        }
+    }
+
+    fn eval_term(&self, term: &str, address: i64) -> Result<i64, String> {
+        if term.is_empty() {
+            return Err("missing value in expression".to_string());
+        }
+
+        if term == "$" {
+            return Ok(address);
+        }
+
+        if term.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_number(term)
+                .and_then(|number| i64::try_from(number).ok())
+                .ok_or_else(|| format!("invalid number '{}'", term));
+        }
+
+        match self.symbols.get(term) {
+            Some(value) => Ok(*value),
+            None => Err(format!("undefined name '{}'", term)),
+        }
+    }
+
+    // Evaluate an operand and check that it fits in the given range
+    fn value(&self, expr: &str, address: i64, min: i64, max: i64) -> Result<i64, String> {
+        let value = self.eval(expr, address)?;
+        if value < min || value > max {
+            return Err(format!("{} is out of range ({} to {:#X})", value, min, max));
+        }
+
+        Ok(value)
+    }
+
+    fn address(&self, expr: &str, address: i64) -> Result<u16, String> {
+        Ok(self.value(expr, address, 0, 0xFFF)? as u16)
+    }
+
+    // Bytes can be given as signed or unsigned values
+    fn byte(&self, expr: &str, address: i64) -> Result<u8, String> {
+        Ok(self.value(expr, address, -128, 0xFF)? as u8)
+    }
+
+    fn nibble(&self, expr: &str, address: i64) -> Result<u8, String> {
+        Ok(self.value(expr, address, 0, 0xF)? as u8)
+    }
+
+    fn encode_data(
+        &self,
+        values: &[String],
+        address: i64,
+        width: usize,
+    ) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+
+        for value in values.iter() {
+            if let (Some(text), 1) = (parse_string(value), width) {
+                bytes.extend_from_slice(text.as_bytes());
+                continue;
+            }
+
+            let position = address + bytes.len() as i64;
+            if width == 1 {
+                bytes.push(self.byte(value, position)?);
+            } else {
+                let word = self.value(value, position, -0x8000, 0xFFFF)? as u16;
+                bytes.extend_from_slice(&word.to_be_bytes());
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    fn encode_instruction(
+        &self,
+        mnemonic: &str,
+        operands: &[String],
+        address: i64,
+    ) -> Result<Vec<u8>, String> {
+        let ops: Vec<Operand> = operands.iter().map(|op| Operand::parse(op)).collect();
+        let expect = |count: usize| -> Result<(), String> {
+            if ops.len() == count {
+                Ok(())
+            } else {
+                Err(format!("{} expects {} operand(s)", mnemonic, count))
+            }
+        };
+        let bad_operands = || format!("invalid operands for {}", mnemonic);
+
+        let instruction = match mnemonic {
+            "CLS" | "RET" | "SCR" | "SCL" | "EXIT" | "LOW" | "HIGH" | "AUDIO" => {
+                expect(0)?;
+                match mnemonic {
+                    "CLS" => Instruction::Cls,
+                    "RET" => Instruction::Ret,
+                    "SCR" => Instruction::Scr,
+                    "SCL" => Instruction::Scl,
+                    "EXIT" => Instruction::Exit,
+                    "LOW" => Instruction::Low,
+                    "HIGH" => Instruction::High,
+                    _ => Instruction::Audio,
+                }
+            }
+            "SCD" => {
+                expect(1)?;
+                Instruction::Scd(self.nibble(operands[0].as_str(), address)?)
+            }
+            "PLANE" => {
+                expect(1)?;
+                Instruction::Plane(self.nibble(operands[0].as_str(), address)?)
+            }
+            "SYS" => {
+                expect(1)?;
+                Instruction::Sys(self.address(operands[0].as_str(), address)?)
+            }
+            "CALL" => {
+                expect(1)?;
+                Instruction::Call(self.address(operands[0].as_str(), address)?)
+            }
+            "JP" => match ops.as_slice() {
+                [Operand::Expr(addr)] => Instruction::Jp(self.address(addr, address)?),
+                [Operand::Register(0), Operand::Expr(addr)] => {
+                    Instruction::JpV0(self.address(addr, address)?)
+                }
+                _ => return Err(bad_operands()),
+            },
+            "SE" | "SNE" => match ops.as_slice() {
+                [Operand::Register(x), Operand::Register(y)] => {
+                    let (x, y) = (*x, *y);
+                    if mnemonic == "SE" {
+                        Instruction::SeReg { x, y }
+                    } else {
+                        Instruction::SneReg { x, y }
+                    }
+                }
+                [Operand::Register(x), Operand::Expr(byte)] => {
+                    let (x, byte) = (*x, self.byte(byte, address)?);
+                    if mnemonic == "SE" {
+                        Instruction::SeByte { x, byte }
+                    } else {
+                        Instruction::SneByte { x, byte }
+                    }
+                }
+                _ => return Err(bad_operands()),
+            },
+            "SAVE" | "LOAD" => {
+                let range = operands.join(",");
+                let registers: Vec<Operand> = range.split(['-', ',']).map(Operand::parse).collect();
+
+                match registers.as_slice() {
+                    [Operand::Register(x), Operand::Register(y)] => {
+                        let (x, y) = (*x, *y);
+                        if mnemonic == "SAVE" {
+                            Instruction::Save { x, y }
+                        } else {
+                            Instruction::Load { x, y }
+                        }
+                    }
+                    _ => return Err(bad_operands()),
+                }
+            }
+            "OR" | "AND" | "XOR" | "SUB" | "SUBN" => match ops.as_slice() {
+                [Operand::Register(x), Operand::Register(y)] => {
+                    let (x, y) = (*x, *y);
+                    match mnemonic {
+                        "OR" => Instruction::Or { x, y },
+                        "AND" => Instruction::And { x, y },
+                        "XOR" => Instruction::Xor { x, y },
+                        "SUB" => Instruction::Sub { x, y },
+                        _ => Instruction::Subn { x, y },
+                    }
+                }
+                _ => return Err(bad_operands()),
+            },
+            "SHR" | "SHL" => {
+                let (x, y) = match ops.as_slice() {
+                    [Operand::Register(x)] => (*x, 0),
+                    [Operand::Register(x), Operand::Register(y)] => (*x, *y),
+                    _ => return Err(bad_operands()),
+                };
+
+                if mnemonic == "SHR" {
+                    Instruction::Shr { x, y }
+                } else {
+                    Instruction::Shl { x, y }
+                }
+            }
+            "ADD" => match ops.as_slice() {
+                [Operand::I, Operand::Register(x)] => Instruction::AddI(*x),
+                [Operand::Register(x), Operand::Register(y)] => {
+                    Instruction::AddReg { x: *x, y: *y }
+                }
+                [Operand::Register(x), Operand::Expr(byte)] => Instruction::AddByte {
+                    x: *x,
+                    byte: self.byte(byte, address)?,
+                },
+                _ => return Err(bad_operands()),
+            },
+            "RND" => match ops.as_slice() {
+                [Operand::Register(x), Operand::Expr(byte)] => Instruction::Rnd {
+                    x: *x,
+                    byte: self.byte(byte, address)?,
+                },
+                _ => return Err(bad_operands()),
+            },
+            "DRW" => match ops.as_slice() {
+                [Operand::Register(x), Operand::Register(y), Operand::Expr(n)] => {
+                    Instruction::Drw {
+                        x: *x,
+                        y: *y,
+                        n: self.nibble(n, address)?,
+                    }
+                }
+                _ => return Err(bad_operands()),
+            },
+            "SKP" | "SKNP" | "PITCH" => match ops.as_slice() {
+                [Operand::Register(x)] => match mnemonic {
+                    "SKP" => Instruction::Skp(*x),
+                    "SKNP" => Instruction::Sknp(*x),
+                    _ => Instruction::Pitch(*x),
+                },
+                _ => return Err(bad_operands()),
+            },
+            "LD" => self.encode_ld(&ops, address).ok_or_else(bad_operands)??,
+            _ => return Err(format!("unknown instruction '{}'", mnemonic)),
+        };
+
+        Ok(instruction.bytes())
+    }
+
+    // The many forms of LD. Returns None if the operands don't match any of them.
+    fn encode_ld(&self, ops: &[Operand], address: i64) -> Option<Result<Instruction, String>> {
+        let instruction = match ops {
+            [Operand::Register(x), Operand::Register(y)] => Instruction::LdReg { x: *x, y: *y },
+            [Operand::Register(x), Operand::Dt] => Instruction::LdVxDt(*x),
+            [Operand::Register(x), Operand::K] => Instruction::LdVxK(*x),
+            [Operand::Register(x), Operand::IndirectI] => Instruction::LdVxI(*x),
+            [Operand::Register(x), Operand::R] => Instruction::LdVxR(*x),
+            [Operand::Register(x), Operand::Expr(byte)] => match self.byte(byte, address) {
+                Ok(byte) => Instruction::LdByte { x: *x, byte },
+                Err(error) => return Some(Err(error)),
+            },
+            [Operand::Dt, Operand::Register(x)] => Instruction::LdDtVx(*x),
+            [Operand::St, Operand::Register(x)] => Instruction::LdStVx(*x),
+            [Operand::F, Operand::Register(x)] => Instruction::LdF(*x),
+            [Operand::Hf, Operand::Register(x)] => Instruction::LdHf(*x),
+            [Operand::B, Operand::Register(x)] => Instruction::LdB(*x),
+            [Operand::R, Operand::Register(x)] => Instruction::LdRVx(*x),
+            [Operand::IndirectI, Operand::Register(x)] => Instruction::LdIVx(*x),
+            [Operand::I, Operand::Long(addr)] => match self.value(addr, address, 0, 0xFFFF) {
+                Ok(addr) => Instruction::LdILong(addr as u16),
+                Err(error) => return Some(Err(error)),
+            },
+            [Operand::I, Operand::Expr(addr)] => match self.address(addr, address) {
+                Ok(addr) => Instruction::LdI(addr),
+                Err(error) => return Some(Err(error)),
+            },
+            _ => return None,
+        };
+
+        Some(Ok(instruction))
+    }
+}
+
+enum Operand {
+    Register(u8),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    // The address in "LD I, long nnnn"
+    Long(String),
+    Expr(String),
+}
+
+impl Operand {
+    fn parse(text: &str) -> Operand {
+        let text = text.trim();
+        let upper = text.to_ascii_uppercase();
+
+        if let Some(register) = parse_register(&upper) {
+            return Operand::Register(register);
+        }
+
+        match upper.as_str() {
+            "I" => Operand::I,
+            "[I]" => Operand::IndirectI,
+            "DT" => Operand::Dt,
+            "ST" => Operand::St,
+            "K" => Operand::K,
+            "F" => Operand::F,
+            "HF" => Operand::Hf,
+            "B" => Operand::B,
+            "R" => Operand::R,
+            _ => {
+                let (word, rest) = split_first_word(text);
+                if word.eq_ignore_ascii_case("long") {
+                    Operand::Long(rest.to_string())
+                } else {
+                    Operand::Expr(text.to_string())
+                }
+            }
+        }
+    }
+}
+
+// Split a line into an optional label and the statement that follows it
+fn parse_line(text: &str) -> Result<(Option<String>, Statement), String> {
+    let (first, rest) = split_first_word(text);
+
+    if let Some(label) = first.strip_suffix(':') {
+        let (_, statement) = parse_line(rest)?;
+        return Ok((Some(label.to_string()), statement));
+    }
+
+    if first.is_empty() {
+        return Ok((None, Statement::Empty));
+    }
+
+    // NAME equ value, or NAME = value
+    let (second, value) = split_first_word(rest);
+    if second.eq_ignore_ascii_case("equ") || second == "=" {
+        return Ok((None, Statement::Equ(first.to_string(), value.to_string())));
+    }
+    if let Some(index) = text.find('=') {
+        let name = text[..index].trim();
+        if is_identifier(name) {
+            let value = text[(index + 1)..].trim();
+            return Ok((None, Statement::Equ(name.to_string(), value.to_string())));
+        }
+    }
+
+    let mnemonic = first.to_ascii_uppercase();
+    let operands = split_operands(rest);
+
+    let statement = match mnemonic.as_str() {
+        "DB" => Statement::Db(operands),
+        "DW" => Statement::Dw(operands),
+        "ORG" => Statement::Org(rest.to_string()),
+        _ => Statement::Instruction(mnemonic, operands),
+    };
+
+    Ok((None, statement))
+}
+
+// Size in bytes that a statement assembles to
+fn statement_size(statement: &Statement) -> Result<i64, String> {
+    let size = match statement {
+        Statement::Instruction(mnemonic, operands) => {
+            let long = mnemonic == "LD"
+                && operands.len() == 2
+                && matches!(Operand::parse(&operands[1]), Operand::Long(_));
+            if long {
+                4
+            } else {
+                2
+            }
+        }
+        Statement::Db(values) => values
+            .iter()
+            .map(|value| parse_string(value).map_or(1, |text| text.len()))
+            .sum(),
+        Statement::Dw(values) => 2 * values.len(),
+        Statement::Org(_) | Statement::Equ(_, _) | Statement::Empty => 0,
+    };
+
+    if let Statement::Db(values) | Statement::Dw(values) = statement {
+        if values.is_empty() {
+            return Err("expected at least one value".to_string());
+        }
+    }
+
+    Ok(size as i64)
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut in_string = false;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &text[..i],
+            _ => {}
+        }
+    }
+
+    text
+}
+
+fn split_first_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.find(char::is_whitespace) {
+        Some(index) => (&text[..index], text[index..].trim()),
+        None => (text, ""),
+    }
+}
+
+// Split on commas that aren't inside a string
+fn split_operands(text: &str) -> Vec<String> {
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let mut in_string = false;
+
+    for c in text.chars() {
+        match c {
+            '"' => {
+                in_string = !in_string;
+                current.push(c);
+            }
+            ',' if !in_string => operands.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+
+    if !current.trim().is_empty() || !operands.is_empty() {
+        operands.push(current);
+    }
+
+    operands.iter().map(|op| op.trim().to_string()).collect()
+}
+
+fn parse_string(text: &str) -> Option<&str> {
+    let text = text.trim();
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        Some(&text[1..(text.len() - 1)])
+    } else {
+        None
+    }
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let number = text.strip_prefix('V')?;
+    if number.len() != 1 {
+        return None;
+    }
+
+    u8::from_str_radix(number, 16).ok()
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn is_reserved(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    parse_register(&upper).is_some()
+        || ["I", "DT", "ST", "K", "F", "HF", "B", "R", "LONG"].contains(&upper.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> AsmError {
+        assemble(source, "test.asm").unwrap_err()
+    }
+
+    #[test]
+    fn labels_constants_and_data() {
+        let source = "
+            ; forward and backward references
+            SPEED equ 3
+            OFFSET = SPEED + 0b10
+        start:
+            LD V0, SPEED
+            LD VA, OFFSET - 1
+            LD I, sprite
+            JP start
+        sprite:
+            DB 0xF0, 0x90
+            DW 0x1234
+        ";
+
+        assert_eq!(
+            assemble(source, "test.asm").unwrap(),
+            vec![0x60, 0x03, 0x6A, 0x04, 0xA2, 0x08, 0x12, 0x00, 0xF0, 0x90, 0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn org_pads_with_zeros() {
+        assert_eq!(
+            assemble("CLS\nORG 0x206\nRET", "test.asm").unwrap(),
+            vec![0x00, 0xE0, 0, 0, 0, 0, 0x00, 0xEE]
+        );
+        assert_eq!(
+            assemble("ORG 0x600\nJP here\nhere: EXIT", "test.asm").unwrap(),
+            vec![0x16, 0x02, 0x00, 0xFD]
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let out_of_range = error("CLS\n\nLD V0, 0x100");
+        assert_eq!(
+            (out_of_range.file.as_str(), out_of_range.line),
+            ("test.asm", 3)
+        );
+
+        assert_eq!(error("JP nowhere").line, 1);
+        assert_eq!(error("CLS\nFROB V0").line, 2);
+        assert_eq!(error("a:\na:").line, 2);
+        assert_eq!(error("PLANE 16").line, 1);
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let dir = std::env::temp_dir().join(format!("chip8-asm-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.asm"), "INCLUDE \"lib/sprites.asm\"\nJP main").unwrap();
+        fs::write(dir.join("lib/sprites.asm"), "main:\nINCLUDE \"more.asm\"").unwrap();
+        fs::write(dir.join("lib/more.asm"), "DB 0xAA, 0xBB").unwrap();
+        fs::write(dir.join("loop.asm"), "INCLUDE \"loop.asm\"").unwrap();
+
+        let rom = assemble_file(&dir.join("main.asm"));
+        let looped = assemble_file(&dir.join("loop.asm"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rom.unwrap(), vec![0xAA, 0xBB, 0x12, 0x00]);
+        assert!(looped.unwrap_err().message.contains("nested too deeply"));
+    }
+}
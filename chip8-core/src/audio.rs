@@ -0,0 +1,294 @@
+use crate::Chip8;
+use std::f32::consts::PI;
+
+/* The sound the machine makes while its sound timer is running, as samples a frontend
+ * can play. Generating samples is kept apart from playing them so the output can be
+ * checked, or written out with encode_wav, without an audio device.
+ *
+ * CHIP-8 and SUPER-CHIP programs get a plain tone. XO-CHIP programs that have loaded
+ * an audio pattern hear that instead: 128 one-bit samples played on a loop, at a rate
+ * set by the pitch register. */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sawtooth,
+    Sine,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name {
+            "square" => Some(Waveform::Square),
+            "triangle" => Some(Waveform::Triangle),
+            "sawtooth" => Some(Waveform::Sawtooth),
+            "sine" => Some(Waveform::Sine),
+            _ => None,
+        }
+    }
+
+    // The waveform's value a fraction of the way through a period, from -1 to 1
+    fn value(self, phase: f32) -> f32 {
+        match self {
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tone {
+    pub waveform: Waveform,
+    // In Hz
+    pub frequency: f32,
+    // From 0 (silent) to 1
+    pub volume: f32,
+}
+
+// Length of one loop of the plain tone, in seconds
+const TONE_SECONDS: f32 = 0.25;
+
+// Bits in an XO-CHIP audio pattern
+const PATTERN_BITS: usize = 128;
+
+impl Default for Tone {
+    fn default() -> Tone {
+        Tone {
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 0.25,
+        }
+    }
+}
+
+impl Tone {
+    /* One loop of the machine's sound at sample_rate, to be played over and over while
+     * the sound timer runs. The loop holds a whole number of periods so it repeats
+     * without clicks, which can shift the pitch very slightly. Call again after an
+     * XO-CHIP program changes its pattern or pitch. */
+    pub fn samples(&self, system: &Chip8, sample_rate: u32) -> Vec<f32> {
+        let pattern = system.audio_pattern();
+
+        if system.xo_chip() && pattern.iter().any(|&byte| byte != 0) {
+            let len = (PATTERN_BITS as f64 * f64::from(sample_rate) / system.audio_playback_rate())
+                .round()
+                .max(1.0) as usize;
+
+            return (0..len)
+                .map(|i| {
+                    let bit = i * PATTERN_BITS / len;
+                    if pattern[bit / 8] & (0x80 >> (bit % 8)) != 0 {
+                        self.volume
+                    } else {
+                        -self.volume
+                    }
+                })
+                .collect();
+        }
+
+        let periods = (self.frequency * TONE_SECONDS).round().max(1.0);
+        let len = (periods * sample_rate as f32 / self.frequency)
+            .round()
+            .max(1.0) as usize;
+
+        (0..len)
+            .map(|i| {
+                let phase = (i as f32 * periods / len as f32).fract();
+                self.waveform.value(phase) * self.volume
+            })
+            .collect()
+    }
+}
+
+// Encode samples from -1 to 1 as a 16-bit mono WAV file
+pub fn encode_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let data_len = samples.len() as u32 * 2;
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, one channel
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    // Bytes per second, bytes per sample and bits per sample
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples.iter() {
+        let value = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16;
+        wav.extend_from_slice(&value.to_le_bytes());
+    }
+
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::Quirks;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    // Loads a pattern that's on for its first 8 bits, and sets the pitch from V0
+    fn xo_machine(pitch: u8) -> Chip8 {
+        let source = format!(
+            "
+                LD V0, {}
+                PITCH V0
+                LD I, pattern
+                AUDIO
+            end:
+                JP end
+            pattern:
+                DB 0xFF, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+            ",
+            pitch
+        );
+        let mut system = Chip8::with_seed(600, Quirks::xo_chip(), 0);
+        system.enable_xo_chip();
+        system
+            .load_rom_bytes(&assemble(&source, "test.asm").unwrap())
+            .unwrap();
+        for _ in 0..4 {
+            system.step().unwrap();
+        }
+
+        system
+    }
+
+    // Number of times the samples go from negative to zero or above, including the wrap
+    fn rising_edges(samples: &[f32]) -> usize {
+        (0..samples.len())
+            .filter(|&i| {
+                let previous = samples[(i + samples.len() - 1) % samples.len()];
+                previous < 0.0 && samples[i] >= 0.0
+            })
+            .count()
+    }
+
+    #[test]
+    fn tone_loops_hold_whole_periods() {
+        let system = Chip8::new(600, Quirks::default());
+
+        for &waveform in [
+            Waveform::Square,
+            Waveform::Triangle,
+            Waveform::Sawtooth,
+            Waveform::Sine,
+        ]
+        .iter()
+        {
+            let tone = Tone {
+                waveform,
+                ..Tone::default()
+            };
+            let samples = tone.samples(&system, SAMPLE_RATE);
+
+            // A quarter of a second of 440 Hz is 110 periods
+            assert_eq!(samples.len(), 11025, "{:?}", waveform);
+            assert_eq!(rising_edges(&samples), 110, "{:?}", waveform);
+        }
+
+        let tone = Tone {
+            frequency: 1000.0,
+            ..Tone::default()
+        };
+        let samples = tone.samples(&system, 8000);
+        assert_eq!(samples.len(), 2000);
+        assert_eq!(
+            &samples[..8],
+            &[0.25, 0.25, 0.25, 0.25, -0.25, -0.25, -0.25, -0.25]
+        );
+    }
+
+    #[test]
+    fn volume_sets_the_amplitude() {
+        let system = Chip8::new(600, Quirks::default());
+
+        for &volume in [0.0, 0.1, 0.5, 1.0].iter() {
+            for &waveform in [Waveform::Square, Waveform::Triangle, Waveform::Sine].iter() {
+                let tone = Tone {
+                    waveform,
+                    frequency: 440.0,
+                    volume,
+                };
+                let peak = tone
+                    .samples(&system, SAMPLE_RATE)
+                    .iter()
+                    .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+
+                assert!((peak - volume).abs() < 0.001, "{:?} {}", waveform, volume);
+            }
+        }
+    }
+
+    #[test]
+    fn xo_chip_pattern_replaces_the_tone() {
+        let tone = Tone::default();
+
+        // Pitch 64 plays the pattern at 4000 bits a second
+        let system = xo_machine(64);
+        assert_eq!(system.audio_playback_rate(), 4000.0);
+        let samples = tone.samples(&system, SAMPLE_RATE);
+        assert_eq!(samples.len(), 1411);
+        assert_eq!(rising_edges(&samples), 1);
+        let on = samples.iter().filter(|&&sample| sample > 0.0).count();
+        assert_eq!(on, 89);
+        assert!(samples[..88].iter().all(|&sample| sample == tone.volume));
+        assert!(samples[89..].iter().all(|&sample| sample == -tone.volume));
+
+        // Every 48 steps of pitch doubles the rate
+        let system = xo_machine(112);
+        assert_eq!(system.audio_playback_rate(), 8000.0);
+        assert_eq!(tone.samples(&system, SAMPLE_RATE).len(), 706);
+
+        // Without XO-CHIP, or with an empty pattern, it's the plain tone
+        let mut system = Chip8::new(600, Quirks::default());
+        assert_eq!(tone.samples(&system, SAMPLE_RATE).len(), 11025);
+        system.enable_xo_chip();
+        assert_eq!(tone.samples(&system, SAMPLE_RATE).len(), 11025);
+    }
+
+    #[test]
+    fn wav_header_describes_the_samples() {
+        let wav = encode_wav(&[0.0, 1.0, -1.0, 2.0, 0.5], 22050);
+        let u16_at = |at: usize| u16::from_le_bytes([wav[at], wav[at + 1]]);
+        let u32_at =
+            |at: usize| u32::from_le_bytes([wav[at], wav[at + 1], wav[at + 2], wav[at + 3]]);
+
+        assert_eq!(wav.len(), 44 + 10);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(4), 36 + 10);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(16), 16);
+        assert_eq!(u16_at(20), 1);
+        assert_eq!(u16_at(22), 1);
+        assert_eq!(u32_at(24), 22050);
+        assert_eq!(u32_at(28), 44100);
+        assert_eq!(u16_at(32), 2);
+        assert_eq!(u16_at(34), 16);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(40), 10);
+
+        let samples: Vec<i16> = (0..5).map(|i| u16_at(44 + 2 * i) as i16).collect();
+        assert_eq!(samples, vec![0, i16::MAX, -i16::MAX, i16::MAX, 16383]);
+    }
+}
@@ -0,0 +1,37 @@
+use chip8_core::asm;
+use chip8_core::cli::flag_value;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 {
+        eprintln!("Usage: chip8-asm <source> [-o <output>]");
+        process::exit(2);
+    }
+
+    let source = Path::new(&args[1]);
+    let rom = match asm::assemble_file(source) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    };
+
+    // Write next to the source as a .ch8 unless told otherwise
+    let output = match flag_value(&args, "-o") {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => source.with_extension("ch8"),
+    };
+
+    if let Err(error) = fs::write(&output, &rom) {
+        eprintln!("{}: {}", output.display(), error);
+        process::exit(1);
+    }
+
+    println!("Wrote {} ({} bytes)", output.display(), rom.len());
+}
@@ -0,0 +1,45 @@
+use chip8_core::cli::{flag_value, parse_number};
+use chip8_core::{disasm, DEFAULT_LOAD_ADDRESS};
+use std::env;
+use std::fs;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 {
+        eprintln!("Usage: chip8-disasm <rom> [--origin <address>] [-o <output>]");
+        process::exit(2);
+    }
+
+    let origin = match flag_value(&args, "--origin") {
+        Some(val) => match parse_number(val) {
+            Some(origin) => origin,
+            None => {
+                eprintln!("Invalid origin: {}", val);
+                process::exit(2);
+            }
+        },
+        None => DEFAULT_LOAD_ADDRESS,
+    };
+
+    let rom = match fs::read(&args[1]) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("{}: {}", args[1], error);
+            process::exit(1);
+        }
+    };
+
+    let listing = disasm::disassemble(&rom, origin);
+
+    match flag_value(&args, "-o") {
+        Some(path) => {
+            if let Err(error) = fs::write(path, listing) {
+                eprintln!("{}: {}", path, error);
+                process::exit(1);
+            }
+        }
+        None => print!("{}", listing),
+    }
+}
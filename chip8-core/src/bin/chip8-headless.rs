@@ -0,0 +1,358 @@
+use chip8_core::audio::{self, Tone};
+use chip8_core::cli::{flag_value, parse_number};
+use chip8_core::movie::{Movie, Playback};
+use chip8_core::palette::Palette;
+use chip8_core::{Chip8, Quirks, Renderer};
+use std::env;
+use std::fs;
+use std::process;
+
+const USAGE: &str = "\
+Usage: chip8-headless <rom> [options]
+
+  --frames <n>          frames to run (default 60, or the length of --movie)
+  --clock <hz>          instructions per second (default 600)
+  --quirks <name>       vip, chip48, schip or xo
+  --xo-chip             enable XO-CHIP instructions and memory
+  --seed <n>            random seed (default 0)
+  --load-address <addr> where the ROM is loaded (default 0x200)
+  --keys <file>         key script, one '<frame> press|release <key>' per line
+  --movie <file>        play back a recorded movie instead
+  --format ascii|pbm    how to dump the screen (default ascii)
+  --output <file>       write the dump to a file instead of stdout
+  --expect <file>       compare the dump with a golden file
+  --update              rewrite the golden file instead of comparing
+  --wav <file>          write the sound played during the run as a WAV file
+  --png <file>          save a screenshot of the final screen as a PNG file
+  --png-scale <n>       size of each pixel in the screenshot (default 1)
+  --theme <name>        screenshot colours: default, amber, green, lcd or
+                        high-contrast";
+
+// Sample rate of the --wav output, which makes exactly 735 samples a frame
+const SAMPLE_RATE: u32 = 44100;
+
+/* Runs a ROM without a window and checks what it leaves on screen, for running test
+ * ROMs in CI. Exits with 1 if the screen doesn't match the golden file or the machine
+ * halts with an error, and 2 for bad arguments. */
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 || args[1].starts_with('-') {
+        usage_error("");
+    }
+
+    // Check the screenshot options before spending time on the run
+    let png_path = flag_value(&args, "--png");
+    let png_scale = match flag_value(&args, "--png-scale") {
+        Some(val) => match val.parse() {
+            Ok(scale) if scale > 0 => scale,
+            _ => usage_error(&format!("invalid value for --png-scale: {}", val)),
+        },
+        None => 1,
+    };
+    let palette = match flag_value(&args, "--theme") {
+        Some(name) => Palette::from_name(name)
+            .unwrap_or_else(|| usage_error(&format!("unknown theme: {}", name))),
+        None => Palette::default(),
+    };
+
+    let rom = fs::read(&args[1]).unwrap_or_else(|error| fail(&format!("{}: {}", args[1], error)));
+
+    let (mut system, mut playback) = match flag_value(&args, "--movie") {
+        Some(path) => {
+            let (system, playback) = fs::read_to_string(path)
+                .map_err(|error| error.to_string())
+                .and_then(|text| Movie::parse(&text).map_err(|error| error.to_string()))
+                .and_then(|movie| {
+                    let system = movie.machine(&rom).map_err(|error| error.to_string())?;
+                    Ok((system, Playback::new(movie)))
+                })
+                .unwrap_or_else(|error| fail(&format!("{}: {}", path, error)));
+
+            (system, Some(playback))
+        }
+        None => (machine(&args, &rom), None),
+    };
+
+    let mut keys = match flag_value(&args, "--keys") {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .unwrap_or_else(|error| fail(&format!("{}: {}", path, error)));
+            parse_keys(&text).unwrap_or_else(|error| fail(&format!("{}: {}", path, error)))
+        }
+        None => Vec::new(),
+    };
+    keys.reverse();
+
+    let frames = match flag_value(&args, "--frames") {
+        Some(val) => val
+            .parse()
+            .unwrap_or_else(|_| usage_error(&format!("invalid frame count: {}", val))),
+        None => match &playback {
+            Some(playback) => playback.movie().end_frame,
+            None => 60,
+        },
+    };
+
+    let wav_path = flag_value(&args, "--wav");
+    let tone = Tone::default();
+    let mut sound = Vec::new();
+
+    while system.frame_count() < frames && !system.exited() {
+        while keys.last().is_some_and(|key| key.0 <= system.frame_count()) {
+            let (_, key, pressed) = keys.pop().unwrap();
+            if pressed {
+                system.press_key(key);
+            } else {
+                system.unpress_key(key);
+            }
+        }
+
+        if let Some(playback) = &mut playback {
+            playback.apply(&mut system);
+        }
+
+        // The sound timer is checked once a frame, which is how often it changes
+        if wav_path.is_some() {
+            let frame_len = (SAMPLE_RATE / 60) as usize;
+
+            if system.sound_timer() > 0 {
+                let samples = tone.samples(&system, SAMPLE_RATE);
+                let start = sound.len();
+                sound.extend((start..(start + frame_len)).map(|i| samples[i % samples.len()]));
+            } else {
+                sound.resize(sound.len() + frame_len, 0.0);
+            }
+        }
+
+        if let Err(error) = system.run_frame() {
+            fail(&format!(
+                "Emulation halted on frame {}: {}",
+                system.frame_count(),
+                error
+            ));
+        }
+    }
+
+    if let Some(path) = wav_path {
+        if let Err(error) = fs::write(path, audio::encode_wav(&sound, SAMPLE_RATE)) {
+            fail(&format!("{}: {}", path, error));
+        }
+    }
+
+    if let Some(path) = png_path {
+        let png = Renderer::new(palette).screenshot(&system, png_scale);
+        if let Err(error) = fs::write(path, png) {
+            fail(&format!("{}: {}", path, error));
+        }
+    }
+
+    let dump = match flag_value(&args, "--format") {
+        None | Some("ascii") => ascii(&system),
+        Some("pbm") => pbm(&system),
+        Some(format) => usage_error(&format!("unknown format: {}", format)),
+    };
+
+    if let Some(path) = flag_value(&args, "--output") {
+        write(path, &dump);
+    }
+
+    match flag_value(&args, "--expect") {
+        Some(path) if args.iter().any(|arg| arg == "--update") => {
+            write(path, &dump);
+            println!("Updated {}", path);
+        }
+        Some(path) => {
+            let expected = fs::read_to_string(path)
+                .unwrap_or_else(|error| fail(&format!("{}: {}", path, error)));
+
+            if let Some(line) = first_difference(&expected, &dump) {
+                eprintln!("Screen doesn't match {} from line {}, got:", path, line);
+                eprint!("{}", dump);
+                process::exit(1);
+            }
+            println!("Screen matches {}", path);
+        }
+        None if flag_value(&args, "--output").is_none() => print!("{}", dump),
+        None => {}
+    }
+}
+
+// Set up a machine from the command line options and load the ROM
+fn machine(args: &[String], rom: &[u8]) -> Chip8 {
+    let number = |flag: &str, default: usize| match flag_value(args, flag) {
+        Some(val) => parse_number(val)
+            .unwrap_or_else(|| usage_error(&format!("invalid value for {}: {}", flag, val))),
+        None => default,
+    };
+
+    let xo_chip = args.iter().any(|arg| arg == "--xo-chip");
+    let quirks = match flag_value(args, "--quirks") {
+        Some(name) => Quirks::from_name(name)
+            .unwrap_or_else(|| usage_error(&format!("unknown quirks profile: {}", name))),
+        None if xo_chip => Quirks::xo_chip(),
+        None => Quirks::default(),
+    };
+
+    let clock_speed = number("--clock", 600);
+    if clock_speed == 0 {
+        usage_error("invalid value for --clock: 0");
+    }
+
+    let mut system = Chip8::with_seed(clock_speed, quirks, number("--seed", 0) as u64);
+    if xo_chip {
+        system.enable_xo_chip();
+    }
+    system.set_load_address(number("--load-address", chip8_core::DEFAULT_LOAD_ADDRESS));
+
+    if let Err(error) = system.load_rom_bytes(rom) {
+        fail(&format!("{}: {}", args[1], error));
+    }
+
+    system
+}
+
+// Key scripts use the same event lines as movies, e.g. "12 press 5"
+fn parse_keys(text: &str) -> Result<Vec<(u64, usize, bool)>, String> {
+    let mut keys = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let event = match words.as_slice() {
+            [frame, action @ ("press" | "release"), key] => frame.parse().ok().and_then(|frame| {
+                let key = usize::from_str_radix(key, 16)
+                    .ok()
+                    .filter(|&key| key < 16)?;
+                Some((frame, key, *action == "press"))
+            }),
+            _ => None,
+        };
+
+        match event {
+            Some(event) => keys.push(event),
+            None => return Err(format!("line {}: invalid key event '{}'", i + 1, line)),
+        }
+    }
+
+    // Events are applied in frame order, keeping the order of ones on the same frame
+    keys.sort_by_key(|event| event.0);
+    Ok(keys)
+}
+
+/* One character per pixel: '.' when blank and '#' for plane 1. XO-CHIP's other plane
+ * combinations show as '+' (plane 2) and '*' (both). */
+fn ascii(system: &Chip8) -> String {
+    let mut dump = String::new();
+
+    for y in 0..system.display_height() {
+        for x in 0..system.display_width() {
+            dump.push(['.', '#', '+', '*'][system.pixel(x, y) as usize]);
+        }
+        dump.push('\n');
+    }
+
+    dump
+}
+
+// A plain PBM image, where any lit plane counts as black
+fn pbm(system: &Chip8) -> String {
+    let (width, height) = (system.display_width(), system.display_height());
+    let mut dump = format!("P1\n{} {}\n", width, height);
+
+    for y in 0..height {
+        let row: Vec<&str> = (0..width)
+            .map(|x| if system.pixel(x, y) != 0 { "1" } else { "0" })
+            .collect();
+        dump.push_str(&row.join(" "));
+        dump.push('\n');
+    }
+
+    dump
+}
+
+// The first line number where two dumps differ, ignoring line ending differences
+fn first_difference(expected: &str, actual: &str) -> Option<usize> {
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    let mut line = 1;
+
+    loop {
+        match (expected_lines.next(), actual_lines.next()) {
+            (None, None) => return None,
+            (a, b) if a.map(str::trim_end) != b.map(str::trim_end) => return Some(line),
+            _ => line += 1,
+        }
+    }
+}
+
+fn write(path: &str, dump: &str) {
+    if let Err(error) = fs::write(path, dump) {
+        fail(&format!("{}: {}", path, error));
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn usage_error(message: &str) -> ! {
+    if !message.is_empty() {
+        eprintln!("{}", message);
+    }
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_scripts_are_sorted_by_frame() {
+        let script = "# start\n12 press 5\n\n3 press a\n  12 release 5  \n3 release A\n";
+        assert_eq!(
+            parse_keys(script),
+            Ok(vec![
+                (3, 0xA, true),
+                (3, 0xA, false),
+                (12, 5, true),
+                (12, 5, false),
+            ])
+        );
+        assert_eq!(parse_keys(""), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn bad_key_events_are_reported_by_line() {
+        let error = |script: &str| parse_keys(script).unwrap_err();
+
+        assert_eq!(
+            error("1 press 1\n\n2 press 10"),
+            "line 3: invalid key event '2 press 10'"
+        );
+        assert!(error("x press 1").starts_with("line 1:"));
+        assert!(error("1 hold 1").starts_with("line 1:"));
+        assert!(error("1 press").starts_with("line 1:"));
+        assert!(error("-1 press 1").starts_with("line 1:"));
+    }
+
+    #[test]
+    fn dumps_differ_from_the_first_changed_line() {
+        assert_eq!(first_difference("..\n##\n", "..\n##\n"), None);
+        // Only line endings and trailing spaces differ
+        assert_eq!(first_difference("..\r\n##  \r\n", "..\n##"), None);
+
+        assert_eq!(first_difference("..\n##\n", "..\n#.\n"), Some(2));
+        assert_eq!(first_difference("..\n##\n", ".#\n##\n"), Some(1));
+        // One dump is longer than the other
+        assert_eq!(first_difference("..\n", "..\n##\n"), Some(2));
+        assert_eq!(first_difference("..\n##\n", "..\n"), Some(2));
+        assert_eq!(first_difference("", "."), Some(1));
+    }
+}
@@ -0,0 +1,53 @@
+/* Command line helpers shared by the emulator, the tools, the debugger and the
+ * assembler, so they all read addresses and options the same way. */
+
+/* Parses decimal numbers, hexadecimal ones starting with 0x and binary ones starting
+ * with 0b. The prefix and hex digits can be in either case. */
+pub fn parse_number(val: &str) -> Option<usize> {
+    let val = val.to_ascii_lowercase();
+
+    if let Some(hex) = val.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = val.strip_prefix("0b") {
+        usize::from_str_radix(binary, 2).ok()
+    } else {
+        val.parse().ok()
+    }
+}
+
+// Returns the argument following a flag such as "--quirks vip"
+pub fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let position = args.iter().position(|arg| arg == flag)?;
+    args.get(position + 1).map(|val| val.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_in_any_base() {
+        assert_eq!(parse_number("512"), Some(512));
+        assert_eq!(parse_number("0x200"), Some(0x200));
+        assert_eq!(parse_number("0XfF"), Some(0xFF));
+        assert_eq!(parse_number("0b1010"), Some(10));
+        assert_eq!(parse_number("0B11"), Some(3));
+
+        for bad in ["", "0x", "0b2", "-1", "12ab", "0x1g", " 1"].iter() {
+            assert_eq!(parse_number(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn flags_take_the_next_argument() {
+        let args: Vec<String> = ["rom.ch8", "--clock", "700", "--xo-chip", "--seed"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+
+        assert_eq!(flag_value(&args, "--clock"), Some("700"));
+        assert_eq!(flag_value(&args, "--xo-chip"), Some("--seed"));
+        assert_eq!(flag_value(&args, "--seed"), None);
+        assert_eq!(flag_value(&args, "--quirks"), None);
+    }
+}
@@ -0,0 +1,1031 @@
+use crate::{Chip8, Chip8Error, Instruction};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+/* An interactive debugger that runs a machine on behalf of a frontend.
+ *
+ * The frontend hands the debugger the time that has passed instead of calling
+ * Chip8::run_for itself, and passes on any commands the user types. While running, the
+ * debugger checks breakpoints, watchpoints and conditions before and after every
+ * instruction, and pauses the machine when one of them triggers. */
+pub struct Debugger {
+    // Breakpoints by address, some only stopping when a condition holds
+    breakpoints: BTreeMap<usize, Option<Condition>>,
+    watchpoints: Vec<Watchpoint>,
+    // Stop when one of these becomes true, along with whether it was true last time
+    conditions: Vec<(Condition, bool)>,
+    state: RunState,
+    // Don't stop at a breakpoint on this address again when resuming from it
+    resume_from: Option<usize>,
+}
+
+enum RunState {
+    Paused,
+    Running,
+    // Running until a CALL at some address returns to the instruction after it
+    StepOver { address: usize, depth: usize },
+    // Running until the current subroutine returns
+    Finish { depth: usize },
+}
+
+// Why the debugger paused the machine
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint {
+        pc: usize,
+    },
+    Watchpoint {
+        pc: usize,
+        address: usize,
+        write: bool,
+    },
+    Condition {
+        pc: usize,
+        condition: String,
+    },
+    // A step, step over or run to a frame boundary finished
+    Stepped {
+        pc: usize,
+    },
+    Returned {
+        pc: usize,
+    },
+    Exited,
+    Error(Chip8Error),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Breakpoint { pc } => write!(f, "breakpoint at {:#05X}", pc),
+            Stop::Watchpoint { pc, address, write } => write!(
+                f,
+                "watchpoint: {:#05X} {} by instruction at {:#05X}",
+                address,
+                if *write { "written" } else { "read" },
+                pc
+            ),
+            Stop::Condition { pc, condition } => {
+                write!(f, "condition {} is true at {:#05X}", condition, pc)
+            }
+            Stop::Stepped { pc } => write!(f, "stopped at {:#05X}", pc),
+            Stop::Returned { pc } => write!(f, "returned to {:#05X}", pc),
+            Stop::Exited => write!(f, "program exited"),
+            Stop::Error(error) => write!(f, "emulation halted: {}", error),
+        }
+    }
+}
+
+// A range of memory that stops the machine when it's read or written
+struct Watchpoint {
+    address: usize,
+    len: usize,
+    read: bool,
+    write: bool,
+}
+
+// Memory an instruction reads or writes, other than fetching the instruction itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: usize,
+    pub len: usize,
+    pub write: bool,
+}
+
+impl Chip8 {
+    // The memory the next instruction will access, if it accesses any
+    pub fn next_memory_access(&self) -> Option<MemoryAccess> {
+        let (io, cpu) = (&self.io, &self.cpu);
+        let index = cpu.index;
+        let access = |len: usize, write: bool| {
+            Some(MemoryAccess {
+                address: index,
+                len,
+                write,
+            })
+        };
+
+        match Instruction::fetch(&io.memory, cpu.pc)? {
+            Instruction::Drw { n, .. } => {
+                let sprite_len = if n == 0 { 32 } else { n as usize };
+                let planes = io.display.planes().count_ones() as usize;
+                access(sprite_len * planes, false)
+            }
+            Instruction::LdB(_) => access(3, true),
+            Instruction::LdIVx(x) => access(x as usize + 1, true),
+            Instruction::LdVxI(x) => access(x as usize + 1, false),
+            Instruction::Save { x, y } => access((x.max(y) - x.min(y)) as usize + 1, true),
+            Instruction::Load { x, y } => access((x.max(y) - x.min(y)) as usize + 1, false),
+            Instruction::Audio => access(16, false),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    // A debugger with the machine paused
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeMap::new(),
+            watchpoints: Vec::new(),
+            conditions: Vec::new(),
+            state: RunState::Paused,
+            resume_from: None,
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        matches!(self.state, RunState::Paused)
+    }
+
+    pub fn pause(&mut self) {
+        self.state = RunState::Paused;
+    }
+
+    pub fn resume(&mut self, system: &Chip8) {
+        self.resume_from = Some(system.pc());
+        self.state = RunState::Running;
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize) {
+        self.breakpoints.insert(address, None);
+    }
+
+    // Returns false if there was no breakpoint at the address
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn add_watchpoint(&mut self, address: usize, len: usize, read: bool, write: bool) {
+        self.watchpoints.push(Watchpoint {
+            address,
+            len,
+            read,
+            write,
+        });
+    }
+
+    // Returns false if no watchpoint starts at the address
+    pub fn remove_watchpoint(&mut self, address: usize) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watch| watch.address != address);
+        self.watchpoints.len() != count
+    }
+
+    /* Run the machine for an amount of time, as Chip8::run_for does, unless paused.
+     * Returns the reason if the machine stopped. */
+    pub fn run_for(&mut self, system: &mut Chip8, elapsed: Duration) -> Option<Stop> {
+        if self.paused() {
+            return None;
+        }
+
+        let result = system.run_for_with(elapsed, |system| match self.step_cycle(system) {
+            Some(stop) => Err(stop),
+            None => Ok(()),
+        });
+
+        match result {
+            Ok(()) => None,
+            Err(stop) => {
+                self.pause();
+                Some(stop)
+            }
+        }
+    }
+
+    // Execute exactly one instruction, ignoring breakpoints
+    pub fn step_instruction(&mut self, system: &mut Chip8) -> Stop {
+        self.pause();
+
+        // Cycles spent waiting for the next frame don't execute anything
+        loop {
+            let executes = system.ready();
+            if let Err(error) = system.step() {
+                return Stop::Error(error);
+            }
+
+            if executes || system.exited() {
+                break;
+            }
+        }
+
+        if system.exited() {
+            Stop::Exited
+        } else {
+            Stop::Stepped { pc: system.pc() }
+        }
+    }
+
+    // Like step_instruction, but a CALL runs until the subroutine returns
+    pub fn step_over(&mut self, system: &mut Chip8) -> Option<Stop> {
+        match system.next_instruction() {
+            Some(Instruction::Call(_)) => {
+                self.resume(system);
+                self.state = RunState::StepOver {
+                    address: system.pc() + 2,
+                    depth: system.stack().len(),
+                };
+                None
+            }
+            _ => Some(self.step_instruction(system)),
+        }
+    }
+
+    // Run until the current subroutine returns
+    pub fn finish(&mut self, system: &Chip8) {
+        self.resume(system);
+        self.state = RunState::Finish {
+            depth: system.stack().len(),
+        };
+    }
+
+    // Run until the timers next count down, stopping early for breakpoints
+    pub fn run_frame(&mut self, system: &mut Chip8) -> Stop {
+        self.resume(system);
+        let result = system.run_frame_with(|system| match self.step_cycle(system) {
+            Some(stop) => Err(stop),
+            None => Ok(()),
+        });
+
+        self.pause();
+        match result {
+            Ok(()) => Stop::Stepped { pc: system.pc() },
+            Err(stop) => stop,
+        }
+    }
+
+    // Run one cycle, checking everything that could stop the machine
+    fn step_cycle(&mut self, system: &mut Chip8) -> Option<Stop> {
+        if system.exited() {
+            return Some(Stop::Exited);
+        }
+
+        let pc = system.pc();
+        let executes = system.ready();
+
+        if executes && self.resume_from != Some(pc) {
+            if let Some(stop) = self.check_before(system) {
+                return Some(stop);
+            }
+        }
+
+        let access = if executes {
+            system.next_memory_access()
+        } else {
+            None
+        };
+
+        if let Err(error) = system.step() {
+            return Some(Stop::Error(error));
+        }
+
+        if executes {
+            self.resume_from = None;
+        }
+
+        if let Some(access) = access {
+            if let Some(stop) = self.check_watchpoints(pc, access) {
+                return Some(stop);
+            }
+        }
+
+        self.check_after(system)
+    }
+
+    fn check_before(&self, system: &Chip8) -> Option<Stop> {
+        let pc = system.pc();
+
+        if let RunState::StepOver { address, depth } = self.state {
+            if pc == address && system.stack().len() == depth {
+                return Some(Stop::Stepped { pc });
+            }
+        }
+
+        match self.breakpoints.get(&pc) {
+            Some(Some(condition)) if condition.holds(system) => Some(Stop::Condition {
+                pc,
+                condition: condition.to_string(),
+            }),
+            Some(None) => Some(Stop::Breakpoint { pc }),
+            _ => None,
+        }
+    }
+
+    fn check_watchpoints(&self, pc: usize, access: MemoryAccess) -> Option<Stop> {
+        let end = access.address.saturating_add(access.len);
+
+        for watch in self.watchpoints.iter() {
+            let kind_matches = if access.write {
+                watch.write
+            } else {
+                watch.read
+            };
+            let start = access.address.max(watch.address);
+
+            if kind_matches && start < end.min(watch.address.saturating_add(watch.len)) {
+                return Some(Stop::Watchpoint {
+                    pc,
+                    address: start,
+                    write: access.write,
+                });
+            }
+        }
+
+        None
+    }
+
+    fn check_after(&mut self, system: &Chip8) -> Option<Stop> {
+        let pc = system.pc();
+
+        if let RunState::Finish { depth } = self.state {
+            if system.stack().len() < depth {
+                return Some(Stop::Returned { pc });
+            }
+        }
+
+        let mut stop = None;
+        for (condition, was_true) in self.conditions.iter_mut() {
+            let holds = condition.holds(system);
+            if holds && !*was_true && stop.is_none() {
+                stop = Some(Stop::Condition {
+                    pc,
+                    condition: condition.to_string(),
+                });
+            }
+            *was_true = holds;
+        }
+
+        if stop.is_none() && system.exited() {
+            return Some(Stop::Exited);
+        }
+
+        stop
+    }
+
+    /* Run a command typed by the user and return what to show them. Type "help" for the
+     * list of commands. */
+    pub fn command(&mut self, system: &mut Chip8, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match self.run_command(system, &words) {
+            Ok(output) => output,
+            Err(error) => format!("error: {}\n", error),
+        }
+    }
+
+    fn run_command(&mut self, system: &mut Chip8, words: &[&str]) -> Result<String, String> {
+        let mut output = String::new();
+
+        match words {
+            [] => {}
+            ["help"] | ["h"] => output.push_str(HELP),
+            ["continue"] | ["c"] => {
+                self.resume(system);
+                output.push_str("running\n");
+            }
+            ["pause"] | ["p"] => {
+                self.pause();
+                show_position(&mut output, system);
+            }
+            ["step"] | ["s"] => {
+                let stop = self.step_instruction(system);
+                writeln!(output, "{}", stop).unwrap();
+                show_position(&mut output, system);
+            }
+            ["step", count] | ["s", count] => {
+                for _ in 0..parse_number(count)? {
+                    match self.step_instruction(system) {
+                        Stop::Stepped { .. } => {}
+                        stop => {
+                            writeln!(output, "{}", stop).unwrap();
+                            break;
+                        }
+                    }
+                }
+                show_position(&mut output, system);
+            }
+            ["next"] | ["n"] => match self.step_over(system) {
+                Some(stop) => {
+                    writeln!(output, "{}", stop).unwrap();
+                    show_position(&mut output, system);
+                }
+                None => output.push_str("running until the call returns\n"),
+            },
+            ["finish"] | ["f"] => {
+                self.finish(system);
+                output.push_str("running until the subroutine returns\n");
+            }
+            ["frame"] => {
+                let stop = self.run_frame(system);
+                writeln!(output, "{}", stop).unwrap();
+                show_position(&mut output, system);
+            }
+            ["break"] | ["b"] => {
+                for (address, condition) in self.breakpoints.iter() {
+                    match condition {
+                        Some(condition) => writeln!(output, "{:#05X} if {}", address, condition),
+                        None => writeln!(output, "{:#05X}", address),
+                    }
+                    .unwrap();
+                }
+                for (condition, _) in self.conditions.iter() {
+                    writeln!(output, "when {}", condition).unwrap();
+                }
+            }
+            ["break", "if", condition @ ..] | ["b", "if", condition @ ..] => {
+                let condition = Condition::parse(condition)?;
+                let holds = condition.holds(system);
+                writeln!(output, "stopping when {}", condition).unwrap();
+                self.conditions.push((condition, holds));
+            }
+            ["break", address, rest @ ..] | ["b", address, rest @ ..] => {
+                let address = parse_number(address)?;
+                let condition = match rest {
+                    [] => None,
+                    ["if", condition @ ..] => Some(Condition::parse(condition)?),
+                    _ => return Err("expected: break <address> [if <condition>]".to_string()),
+                };
+
+                writeln!(output, "breakpoint at {:#05X}", address).unwrap();
+                self.breakpoints.insert(address, condition);
+            }
+            ["delete", "all"] => {
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+                self.conditions.clear();
+                output.push_str("deleted all breakpoints, watchpoints and conditions\n");
+            }
+            ["delete", address] | ["d", address] => {
+                let address = parse_number(address)?;
+                if !self.remove_breakpoint(address) && !self.remove_watchpoint(address) {
+                    return Err(format!("nothing set at {:#05X}", address));
+                }
+            }
+            ["watch"] | ["w"] => {
+                for watch in self.watchpoints.iter() {
+                    let kind = match (watch.read, watch.write) {
+                        (true, true) => "rw",
+                        (true, false) => "r",
+                        _ => "w",
+                    };
+                    writeln!(output, "{:#05X} {} {}", watch.address, watch.len, kind).unwrap();
+                }
+            }
+            ["watch", address, rest @ ..] | ["w", address, rest @ ..] => {
+                let address = parse_number(address)?;
+                let (len, kind) = match rest {
+                    [] => (1, "w"),
+                    [len] => (parse_number(len)?, "w"),
+                    [len, kind] => (parse_number(len)?, *kind),
+                    _ => return Err("expected: watch <address> [length] [r|w|rw]".to_string()),
+                };
+                let (read, write) = match kind {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    _ => return Err(format!("unknown watch type '{}'", kind)),
+                };
+
+                self.add_watchpoint(address, len, read, write);
+                writeln!(output, "watching {} bytes at {:#05X}", len, address).unwrap();
+            }
+            ["regs"] | ["r"] => {
+                show_registers(&mut output, system);
+                show_position(&mut output, system);
+            }
+            ["set", name, value] => {
+                let value = parse_number(value)?;
+                set_register(system, name, value)?;
+                show_registers(&mut output, system);
+            }
+            ["mem", address] | ["m", address] => {
+                show_memory(&mut output, system, parse_number(address)?, 64)
+            }
+            ["mem", address, len] | ["m", address, len] => show_memory(
+                &mut output,
+                system,
+                parse_number(address)?,
+                parse_number(len)?,
+            ),
+            ["poke", address, bytes @ ..] if !bytes.is_empty() => {
+                let address = parse_number(address)?;
+                let memory = system.memory_mut();
+
+                for (i, byte) in bytes.iter().enumerate() {
+                    let value = parse_number(byte)?;
+                    if value > 0xFF {
+                        return Err(format!("{} doesn't fit in a byte", byte));
+                    }
+
+                    let target = address.saturating_add(i);
+                    match memory.get_mut(target) {
+                        Some(cell) => *cell = value as u8,
+                        None => return Err(format!("{:#06X} is out of range", target)),
+                    }
+                }
+            }
+            ["dis"] => show_disassembly(&mut output, self, system, system.pc(), 10),
+            ["dis", address] => {
+                show_disassembly(&mut output, self, system, parse_number(address)?, 10)
+            }
+            ["dis", address, count] => show_disassembly(
+                &mut output,
+                self,
+                system,
+                parse_number(address)?,
+                parse_number(count)?,
+            ),
+            ["stack"] => {
+                for (depth, address) in system.stack().iter().enumerate().rev() {
+                    writeln!(output, "{:2}: {:#05X}", depth, address).unwrap();
+                }
+            }
+            _ => return Err(format!("unknown command '{}', try help", words.join(" "))),
+        }
+
+        Ok(output)
+    }
+}
+
+const HELP: &str = "\
+continue, c              run until something stops the machine
+pause, p                 stop running
+step, s [count]          execute one instruction, or count of them
+next, n                  step, running CALLs until they return
+finish, f                run until the current subroutine returns
+frame                    run until the next 60 Hz frame
+break, b                 list breakpoints
+break <addr> [if <cond>] stop at an address, optionally only when cond holds
+break if <cond>          stop whenever cond becomes true, e.g. V3 == 0x10
+watch, w                 list watchpoints
+watch <addr> [len] [r|w|rw]  stop when memory is read and/or written
+delete <addr>, delete all    remove breakpoints and watchpoints
+regs, r                  show registers
+set <reg> <value>        change V0-VF, I, PC, DT or ST
+mem, m <addr> [len]      show memory
+poke <addr> <bytes...>   change memory
+dis [addr] [count]       disassemble, from PC by default
+stack                    show return addresses
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    Register(usize),
+    Index,
+    Pc,
+    Sp,
+    DelayTimer,
+    SoundTimer,
+}
+
+impl Target {
+    fn parse(name: &str) -> Option<Target> {
+        let upper = name.to_ascii_uppercase();
+
+        let target = match upper.as_str() {
+            "I" => Target::Index,
+            "PC" => Target::Pc,
+            "SP" => Target::Sp,
+            "DT" => Target::DelayTimer,
+            "ST" => Target::SoundTimer,
+            _ => {
+                let number = upper.strip_prefix('V')?;
+                if number.len() != 1 {
+                    return None;
+                }
+                Target::Register(usize::from_str_radix(number, 16).ok()?)
+            }
+        };
+
+        Some(target)
+    }
+
+    fn value(self, system: &Chip8) -> usize {
+        match self {
+            Target::Register(x) => system.register(x) as usize,
+            Target::Index => system.index(),
+            Target::Pc => system.pc(),
+            Target::Sp => system.stack().len(),
+            Target::DelayTimer => system.delay_timer() as usize,
+            Target::SoundTimer => system.sound_timer() as usize,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Register(x) => write!(f, "V{:X}", x),
+            Target::Index => write!(f, "I"),
+            Target::Pc => write!(f, "PC"),
+            Target::Sp => write!(f, "SP"),
+            Target::DelayTimer => write!(f, "DT"),
+            Target::SoundTimer => write!(f, "ST"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<", Comparison::Less),
+    ("<=", Comparison::LessOrEqual),
+    (">", Comparison::Greater),
+    (">=", Comparison::GreaterOrEqual),
+];
+
+// A comparison between a register and a value, such as "V3 == 0x10"
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Condition {
+    target: Target,
+    comparison: Comparison,
+    value: usize,
+}
+
+impl Condition {
+    fn parse(words: &[&str]) -> Result<Condition, String> {
+        let (target, comparison, value) = match words {
+            [target, comparison, value] => (target, comparison, value),
+            _ => return Err("expected a condition like V3 == 0x10".to_string()),
+        };
+
+        let target =
+            Target::parse(target).ok_or_else(|| format!("unknown register '{}'", target))?;
+        let comparison = COMPARISONS
+            .iter()
+            .find(|(symbol, _)| symbol == comparison)
+            .map(|(_, comparison)| *comparison)
+            .ok_or_else(|| format!("unknown comparison '{}'", comparison))?;
+
+        Ok(Condition {
+            target,
+            comparison,
+            value: parse_number(value)?,
+        })
+    }
+
+    fn holds(&self, system: &Chip8) -> bool {
+        let current = self.target.value(system);
+
+        match self.comparison {
+            Comparison::Equal => current == self.value,
+            Comparison::NotEqual => current != self.value,
+            Comparison::Less => current < self.value,
+            Comparison::LessOrEqual => current <= self.value,
+            Comparison::Greater => current > self.value,
+            Comparison::GreaterOrEqual => current >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = COMPARISONS
+            .iter()
+            .find(|(_, comparison)| *comparison == self.comparison)
+            .map_or("?", |(symbol, _)| symbol);
+
+        write!(f, "{} {} {:#X}", self.target, symbol, self.value)
+    }
+}
+
+fn set_register(system: &mut Chip8, name: &str, value: usize) -> Result<(), String> {
+    let target = Target::parse(name).ok_or_else(|| format!("unknown register '{}'", name))?;
+    let memory_size = system.memory().len();
+    let address = || {
+        if value < memory_size {
+            Ok(value)
+        } else {
+            Err(format!("{:#X} is outside memory", value))
+        }
+    };
+    let byte = || {
+        if value <= 0xFF {
+            Ok(value as u8)
+        } else {
+            Err(format!("{:#X} doesn't fit in a byte", value))
+        }
+    };
+
+    match target {
+        Target::Register(x) => system.set_register(x, byte()?),
+        Target::Index => system.set_index(address()?),
+        Target::Pc => system.set_pc(address()?),
+        Target::DelayTimer => system.set_delay_timer(byte()?),
+        Target::SoundTimer => system.set_sound_timer(byte()?),
+        Target::Sp => return Err("SP can't be changed".to_string()),
+    }
+
+    Ok(())
+}
+
+fn show_registers(output: &mut String, system: &Chip8) {
+    writeln!(
+        output,
+        "PC {:#05X}  I {:#05X}  SP {}  DT {}  ST {}",
+        system.pc(),
+        system.index(),
+        system.stack().len(),
+        system.delay_timer(),
+        system.sound_timer()
+    )
+    .unwrap();
+
+    for row in 0..2 {
+        let registers: Vec<String> = (0..8)
+            .map(|i| row * 8 + i)
+            .map(|x| format!("V{:X} {:02X}", x, system.register(x)))
+            .collect();
+        writeln!(output, "{}", registers.join("  ")).unwrap();
+    }
+}
+
+fn show_position(output: &mut String, system: &Chip8) {
+    match system.next_instruction() {
+        Some(instruction) => writeln!(output, "{:#05X}: {}", system.pc(), instruction),
+        None => writeln!(output, "{:#05X}: out of memory", system.pc()),
+    }
+    .unwrap();
+}
+
+fn show_memory(output: &mut String, system: &Chip8, address: usize, len: usize) {
+    let memory = system.memory();
+    let end = address.saturating_add(len).min(memory.len());
+
+    for start in (address..end).step_by(16) {
+        let bytes: Vec<String> = memory[start..(start + 16).min(end)]
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        writeln!(output, "{:#06X}: {}", start, bytes.join(" ")).unwrap();
+    }
+}
+
+fn show_disassembly(
+    output: &mut String,
+    debugger: &Debugger,
+    system: &Chip8,
+    mut address: usize,
+    count: usize,
+) {
+    for _ in 0..count {
+        let instruction = match Instruction::fetch(system.memory(), address) {
+            Some(instruction) => instruction,
+            None => break,
+        };
+
+        let current = if address == system.pc() { '>' } else { ' ' };
+        let breakpoint = if debugger.breakpoints.contains_key(&address) {
+            '*'
+        } else {
+            ' '
+        };
+        writeln!(
+            output,
+            "{}{} {:#05X}: {}",
+            current, breakpoint, address, instruction
+        )
+        .unwrap();
+
+        address += instruction.size();
+    }
+}
+
+// cli::parse_number, with the error shown for a bad number
+fn parse_number(val: &str) -> Result<usize, String> {
+    crate::cli::parse_number(val).ok_or_else(|| format!("invalid number '{}'", val))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Quirks;
+
+    // LD I, 0x300; LD [I], V0; JP 0x204
+    const ROM: [u8; 6] = [0xA3, 0x00, 0xF0, 0x55, 0x12, 0x04];
+
+    fn machine() -> Chip8 {
+        let mut system = Chip8::with_seed(600, Quirks::default(), 0);
+        system.load_rom_bytes(&ROM).unwrap();
+        system
+    }
+
+    #[test]
+    fn huge_lengths_and_addresses_dont_overflow() {
+        let mut system = machine();
+        let mut debugger = Debugger::new();
+        let max = format!("{:#X}", usize::MAX);
+
+        let dump = debugger.command(&mut system, &format!("mem 0xFF0 {}", max));
+        assert!(dump.starts_with("0x0FF0: "));
+        debugger.command(&mut system, &format!("mem {} {}", max, max));
+        assert!(debugger
+            .command(&mut system, &format!("poke {} 1 2", max))
+            .contains("out of range"));
+
+        debugger.command(&mut system, &format!("watch {} {}", max, max));
+        debugger.command(&mut system, &format!("watch 0x300 {}", max));
+        debugger.command(&mut system, "continue");
+
+        let stop = debugger.run_for(&mut system, Duration::from_millis(100));
+        assert_eq!(
+            stop,
+            Some(Stop::Watchpoint {
+                pc: 0x202,
+                address: 0x300,
+                write: true
+            })
+        );
+    }
+
+    // Counts up in V0, storing it at 0x300 from a subroutine
+    const PROGRAM: &str = "
+            LD V0, 0
+        loop:
+            ADD V0, 1
+            CALL store
+            JP loop
+        store:
+            LD I, 0x300
+            LD [I], V0
+            RET
+    ";
+
+    fn program() -> Chip8 {
+        let mut system = Chip8::with_seed(600, Quirks::default(), 0);
+        let rom = crate::asm::assemble(PROGRAM, "test.asm").unwrap();
+        system.load_rom_bytes(&rom).unwrap();
+        system
+    }
+
+    fn run(debugger: &mut Debugger, system: &mut Chip8) -> Option<Stop> {
+        debugger.run_for(system, Duration::from_millis(100))
+    }
+
+    #[test]
+    fn breakpoints_stop_before_the_instruction() {
+        let (mut system, mut debugger) = (program(), Debugger::new());
+        debugger.command(&mut system, "break 0x208");
+        assert_eq!(run(&mut debugger, &mut system), None);
+
+        debugger.command(&mut system, "continue");
+        assert_eq!(
+            run(&mut debugger, &mut system),
+            Some(Stop::Breakpoint { pc: 0x208 })
+        );
+        assert!(debugger.paused());
+        assert_eq!(system.register(0), 1);
+
+        // Continuing doesn't stop at the same breakpoint straight away
+        debugger.command(&mut system, "c");
+        assert_eq!(
+            run(&mut debugger, &mut system),
+            Some(Stop::Breakpoint { pc: 0x208 })
+        );
+        assert_eq!(system.register(0), 2);
+
+        debugger.command(&mut system, "delete 0x208");
+        debugger.command(&mut system, "c");
+        assert_eq!(run(&mut debugger, &mut system), None);
+    }
+
+    #[test]
+    fn conditions_stop_when_they_become_true() {
+        let (mut system, mut debugger) = (program(), Debugger::new());
+        debugger.command(&mut system, "break 0x208 if V0 == 3");
+        debugger.command(&mut system, "c");
+        assert_eq!(
+            run(&mut debugger, &mut system),
+            Some(Stop::Condition {
+                pc: 0x208,
+                condition: "V0 == 0x3".to_string()
+            })
+        );
+
+        debugger.command(&mut system, "delete all");
+        debugger.command(&mut system, "break if V0 >= 5");
+        debugger.command(&mut system, "c");
+        let stop = run(&mut debugger, &mut system);
+        assert_eq!(
+            stop.unwrap().to_string(),
+            "condition V0 >= 0x5 is true at 0x204"
+        );
+        assert_eq!(system.register(0), 5);
+
+        // Still true, so it doesn't stop again
+        debugger.command(&mut system, "c");
+        assert_eq!(run(&mut debugger, &mut system), None);
+    }
+
+    #[test]
+    fn next_and_finish_run_whole_subroutines() {
+        let (mut system, mut debugger) = (program(), Debugger::new());
+        debugger.command(&mut system, "step 2");
+        assert_eq!(system.pc(), 0x204);
+
+        // Stepping over the CALL runs until it returns
+        assert_eq!(
+            debugger.command(&mut system, "next"),
+            "running until the call returns\n"
+        );
+        assert_eq!(
+            run(&mut debugger, &mut system),
+            Some(Stop::Stepped { pc: 0x206 })
+        );
+        assert_eq!(system.memory()[0x300], 1);
+        assert!(system.stack().is_empty());
+
+        // Other instructions are stepped as usual
+        assert!(debugger
+            .command(&mut system, "n")
+            .starts_with("stopped at 0x202"));
+
+        debugger.command(&mut system, "step 3");
+        assert_eq!((system.pc(), system.stack().len()), (0x20A, 1));
+        debugger.command(&mut system, "finish");
+        assert_eq!(
+            run(&mut debugger, &mut system),
+            Some(Stop::Returned { pc: 0x206 })
+        );
+        assert_eq!(system.memory()[0x300], 2);
+    }
+
+    #[test]
+    fn watchpoints_stop_on_matching_accesses() {
+        let (mut system, mut debugger) = (program(), Debugger::new());
+        debugger.command(&mut system, "watch 0x2FF 1");
+        debugger.command(&mut system, "watch 0x301 4 r");
+        debugger.command(&mut system, "c");
+        assert_eq!(run(&mut debugger, &mut system), None);
+
+        debugger.command(&mut system, "watch 0x2F0 0x11 rw");
+        debugger.command(&mut system, "c");
+        let stop = run(&mut debugger, &mut system).unwrap();
+        assert_eq!(
+            stop,
+            Stop::Watchpoint {
+                pc: 0x20A,
+                address: 0x300,
+                write: true
+            }
+        );
+        assert_eq!(
+            stop.to_string(),
+            "watchpoint: 0x300 written by instruction at 0x20A"
+        );
+
+        let listing = debugger.command(&mut system, "watch");
+        assert_eq!(listing, "0x2FF 1 w\n0x301 4 r\n0x2F0 17 rw\n");
+    }
+
+    #[test]
+    fn registers_can_be_set() {
+        let (mut system, mut debugger) = (program(), Debugger::new());
+        debugger.command(&mut system, "set v3 0x42");
+        debugger.command(&mut system, "set DT 9");
+        debugger.command(&mut system, "set I 0xFFF");
+        debugger.command(&mut system, "set PC 0x20A");
+        assert_eq!(system.register(3), 0x42);
+        assert_eq!(system.delay_timer(), 9);
+        assert_eq!((system.index(), system.pc()), (0xFFF, 0x20A));
+
+        assert_eq!(
+            debugger.command(&mut system, "set I 0x1000"),
+            "error: 0x1000 is outside memory\n"
+        );
+        assert_eq!(
+            debugger.command(&mut system, &format!("set PC {}", usize::MAX)),
+            format!("error: {:#X} is outside memory\n", usize::MAX)
+        );
+        assert_eq!(
+            debugger.command(&mut system, "set V0 256"),
+            "error: 0x100 doesn't fit in a byte\n"
+        );
+        assert!(debugger
+            .command(&mut system, "set SP 1")
+            .contains("can't be changed"));
+        assert_eq!((system.index(), system.pc()), (0xFFF, 0x20A));
+
+        // XO-CHIP machines have 64 KiB to point at
+        system.enable_xo_chip();
+        debugger.command(&mut system, "set I 0xFFFF");
+        assert_eq!(system.index(), 0xFFFF);
+    }
+}
@@ -0,0 +1,246 @@
+use crate::Instruction;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/* Produce an assembly listing for a ROM loaded at origin.
+ *
+ * Code is found by following every path of execution from the origin, so anything that
+ * can't be reached (sprites, tables, padding) is listed as DB data instead of being
+ * misread as instructions. JP and CALL targets get labels, and every line is annotated
+ * with its address and raw bytes. Assembling the listing gives back the original ROM. */
+pub fn disassemble(rom: &[u8], origin: usize) -> String {
+    let code = trace_code(rom, origin);
+    let lines = layout(rom, origin, &code);
+    let labels = label_targets(&lines);
+
+    let mut listing = String::new();
+    writeln!(listing, "; {} bytes, loaded at {:#05X}", rom.len(), origin).unwrap();
+    writeln!(listing).unwrap();
+
+    if origin != crate::DEFAULT_LOAD_ADDRESS {
+        writeln!(listing, "    ORG {:#05X}", origin).unwrap();
+        writeln!(listing).unwrap();
+    }
+
+    for line in lines.iter() {
+        if let Some(label) = labels.get(&line.address()) {
+            writeln!(listing, "{}:", label).unwrap();
+        }
+
+        match *line {
+            Line::Code(address, instruction) => {
+                let raw: Vec<String> = instruction
+                    .bytes()
+                    .iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect();
+                let text = format_instruction(&instruction, &labels);
+
+                writeln!(
+                    listing,
+                    "    {:<24}; {:#05X}  {}",
+                    text,
+                    address,
+                    raw.join(" ")
+                )
+                .unwrap();
+            }
+            Line::Data(address, byte) => {
+                // Show the bits as pixels, since most data in a ROM is sprites
+                let pixels: String = (0..8)
+                    .map(|bit| if byte & (0x80 >> bit) > 0 { '#' } else { '.' })
+                    .collect();
+                let text = format!("DB {:#04X}", byte);
+
+                writeln!(listing, "    {:<24}; {:#05X}  {}", text, address, pixels).unwrap();
+            }
+        }
+    }
+
+    listing
+}
+
+/* Addresses of every instruction that can be reached from the origin. Paths end at
+ * RET, EXIT, computed jumps and anything that isn't a valid instruction. */
+pub fn trace_code(rom: &[u8], origin: usize) -> BTreeSet<usize> {
+    let end = origin + rom.len();
+    let mut code = BTreeSet::new();
+    let mut pending = vec![origin];
+
+    while let Some(address) = pending.pop() {
+        if address < origin || address >= end || code.contains(&address) {
+            continue;
+        }
+
+        let instruction = match fetch(rom, origin, address) {
+            Some(Instruction::Unknown(_)) | None => continue,
+            Some(instruction) => instruction,
+        };
+
+        code.insert(address);
+
+        let next = address + instruction.size();
+        match instruction {
+            Instruction::Jp(addr) => pending.push(addr as usize),
+            Instruction::Call(addr) => {
+                pending.push(addr as usize);
+                pending.push(next);
+            }
+            Instruction::Ret | Instruction::Exit | Instruction::JpV0(_) => {}
+            Instruction::SeByte { .. }
+            | Instruction::SneByte { .. }
+            | Instruction::SeReg { .. }
+            | Instruction::SneReg { .. }
+            | Instruction::Skp(_)
+            | Instruction::Sknp(_) => {
+                pending.push(next);
+
+                if let Some(skipped) = fetch(rom, origin, next) {
+                    pending.push(next + skipped.size());
+                }
+            }
+            _ => pending.push(next),
+        }
+    }
+
+    code
+}
+
+enum Line {
+    Code(usize, Instruction),
+    Data(usize, u8),
+}
+
+impl Line {
+    fn address(&self) -> usize {
+        match *self {
+            Line::Code(address, _) | Line::Data(address, _) => address,
+        }
+    }
+}
+
+/* Split the ROM into lines of code and data. An instruction that would overlap the start
+ * of another one (from a jump into the middle of it) is listed as data instead. */
+fn layout(rom: &[u8], origin: usize, code: &BTreeSet<usize>) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut address = origin;
+
+    while address < origin + rom.len() {
+        let instruction = if code.contains(&address) {
+            fetch(rom, origin, address)
+        } else {
+            None
+        };
+
+        match instruction {
+            Some(instruction)
+                if code
+                    .range((address + 1)..(address + instruction.size()))
+                    .next()
+                    .is_none() =>
+            {
+                lines.push(Line::Code(address, instruction));
+                address += instruction.size();
+            }
+            _ => {
+                lines.push(Line::Data(address, rom[address - origin]));
+                address += 1;
+            }
+        }
+    }
+
+    lines
+}
+
+/* Name the targets of jumps and calls. Targets that don't start a line, or are outside
+ * the ROM, are left as plain addresses. */
+fn label_targets(lines: &[Line]) -> BTreeMap<usize, String> {
+    let starts: BTreeSet<usize> = lines.iter().map(|line| line.address()).collect();
+    let mut labels = BTreeMap::new();
+
+    for line in lines.iter() {
+        let target = match *line {
+            Line::Code(_, Instruction::Call(addr))
+            | Line::Code(_, Instruction::Jp(addr))
+            | Line::Code(_, Instruction::JpV0(addr)) => addr as usize,
+            _ => continue,
+        };
+
+        if !starts.contains(&target) {
+            continue;
+        }
+
+        // A subroutine name wins over a jump label for the same address
+        if let Line::Code(_, Instruction::Call(_)) = *line {
+            labels.insert(target, format!("sub_{:03X}", target));
+        } else {
+            labels
+                .entry(target)
+                .or_insert_with(|| format!("label_{:03X}", target));
+        }
+    }
+
+    labels
+}
+
+fn format_instruction(instruction: &Instruction, labels: &BTreeMap<usize, String>) -> String {
+    let (mnemonic, addr) = match *instruction {
+        Instruction::Jp(addr) => ("JP", addr),
+        Instruction::Call(addr) => ("CALL", addr),
+        Instruction::JpV0(addr) => ("JP V0,", addr),
+        _ => return instruction.to_string(),
+    };
+
+    match labels.get(&(addr as usize)) {
+        Some(label) => format!("{} {}", mnemonic, label),
+        None => instruction.to_string(),
+    }
+}
+
+fn fetch(rom: &[u8], origin: usize, address: usize) -> Option<Instruction> {
+    Instruction::fetch(rom, address.checked_sub(origin)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::DEFAULT_LOAD_ADDRESS;
+
+    fn round_trip(rom: &[u8], origin: usize) {
+        let listing = disassemble(rom, origin);
+        let assembled = assemble(&listing, "listing.asm")
+            .unwrap_or_else(|error| panic!("{}\n{}", error, listing));
+
+        assert_eq!(assembled, rom, "\n{}", listing);
+    }
+
+    #[test]
+    fn every_opcode_round_trips() {
+        for op_code in 0..=0xFFFFu32 {
+            round_trip(&(op_code as u16).to_be_bytes(), DEFAULT_LOAD_ADDRESS);
+        }
+    }
+
+    #[test]
+    fn long_load_round_trips() {
+        round_trip(&[0xF0, 0x00, 0x12, 0x34, 0x12, 0x00], DEFAULT_LOAD_ADDRESS);
+    }
+
+    #[test]
+    fn plane_with_any_mask_round_trips() {
+        round_trip(&[0xFC, 0x01, 0xF3, 0x01], DEFAULT_LOAD_ADDRESS);
+        assert!(disassemble(&[0xFC, 0x01], DEFAULT_LOAD_ADDRESS).contains("PLANE 12"));
+    }
+
+    #[test]
+    fn code_and_data_round_trip() {
+        // Jumps over a sprite, calls a subroutine and loops forever
+        let rom = [
+            0x12, 0x08, 0xF0, 0x90, 0x90, 0x90, 0xF0, 0x00, 0xA2, 0x02, 0x22, 0x10, 0x12, 0x0C,
+            0x00, 0x00, 0xD0, 0x15, 0x00, 0xEE,
+        ];
+        round_trip(&rom, DEFAULT_LOAD_ADDRESS);
+        round_trip(&rom, 0x600);
+    }
+}
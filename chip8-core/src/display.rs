@@ -0,0 +1,361 @@
+// Screen sizes in low resolution (CHIP-8) and high resolution (SUPER-CHIP) mode
+const LORES_SIZE: (usize, usize) = (64, 32);
+const HIRES_SIZE: (usize, usize) = (128, 64);
+
+// Regular CHIP-8 and SUPER-CHIP programs only draw on plane 1; XO-CHIP adds plane 2
+const PLANE_COUNT: usize = 2;
+
+/* The emulated screen, stored as one bit per pixel for each bitplane. Every row of a
+ * plane is a u128, wide enough for high resolution, with the leftmost pixel in the top
+ * bit. In low resolution only the top 64 bits are used. Sprites are XORed on a whole
+ * row at a time, and nothing here knows about colours: the renderer turns the planes
+ * into RGBA when a frame is presented. */
+pub struct Display {
+    hires: bool,
+    // Bitmask of the planes affected by CLS, scrolling and DRW
+    planes: u8,
+    rows: [Vec<u128>; PLANE_COUNT],
+}
+
+impl Display {
+    pub fn new() -> Display {
+        let (_, h) = LORES_SIZE;
+
+        Display {
+            hires: false,
+            planes: 1,
+            rows: [vec![0; h], vec![0; h]],
+        }
+    }
+
+    /* Rebuild a display from the packed rows returned by to_bytes. Returns None if
+     * there are the wrong number of bytes or planes isn't a valid selection. */
+    pub fn from_bytes(hires: bool, planes: u8, bytes: &[u8]) -> Option<Display> {
+        let (w, h) = Display::resolution(hires);
+        let row_len = w / 8;
+        if bytes.len() != PLANE_COUNT * h * row_len || planes > 0x03 {
+            return None;
+        }
+
+        let mut rows = [vec![0; h], vec![0; h]];
+        for (row, chunk) in rows.iter_mut().flatten().zip(bytes.chunks(row_len)) {
+            let mut padded = [0; 16];
+            padded[..row_len].copy_from_slice(chunk);
+            *row = u128::from_be_bytes(padded);
+        }
+
+        Some(Display {
+            hires,
+            planes,
+            rows,
+        })
+    }
+
+    // Every row of both planes, using only as many bytes per row as the screen is wide
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (w, _) = self.size();
+
+        self.rows
+            .iter()
+            .flatten()
+            .flat_map(|row| row.to_be_bytes()[..(w / 8)].to_vec())
+            .collect()
+    }
+
+    pub fn resolution(hires: bool) -> (usize, usize) {
+        if hires {
+            HIRES_SIZE
+        } else {
+            LORES_SIZE
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        Display::resolution(self.hires)
+    }
+
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+
+    // The bitplanes switched on at (x, y), which must be on screen
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        let bit = 127 - x;
+        let plane1 = (self.rows[0][y] >> bit) & 1;
+        let plane2 = (self.rows[1][y] >> bit) & 1;
+
+        (plane1 | plane2 << 1) as u8
+    }
+
+    // A row of one plane (0 or 1), leftmost pixel in the top bit
+    pub fn row(&self, plane: usize, y: usize) -> u128 {
+        self.rows[plane][y]
+    }
+
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & 0x03;
+    }
+
+    // Switching resolution clears the whole screen, including unselected planes
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+
+        let (_, h) = self.size();
+        self.rows = [vec![0; h], vec![0; h]];
+    }
+
+    // Clear the selected planes
+    pub fn clear(&mut self) {
+        for plane in self.selected() {
+            for row in self.rows[plane].iter_mut() {
+                *row = 0;
+            }
+        }
+    }
+
+    /* Move the selected planes by (dx, dy) pixels. Pixels scrolled off the edge are lost
+     * and the uncovered area is cleared. */
+    pub fn scroll(&mut self, offset: (isize, isize)) {
+        let (_, h) = self.size();
+        let (dx, dy) = offset;
+        let mask = self.row_mask();
+
+        for plane in self.selected() {
+            let previous = self.rows[plane].clone();
+
+            for (y, row) in self.rows[plane].iter_mut().enumerate() {
+                let from_y = y as isize - dy;
+                let moved = if from_y < 0 || from_y >= h as isize {
+                    0
+                } else {
+                    previous[from_y as usize]
+                };
+
+                *row = if dx >= 0 { moved >> dx } else { moved << -dx } & mask;
+            }
+        }
+    }
+
+    /* XOR a sprite onto a plane (1 or 2) with its top left corner at coords. Sprites
+     * are 8 pixels wide, or 16 for DRW Vx, Vy, 0, and each row of the sprite is
+     * sprite_width / 8 bytes. When clipping, only the starting position wraps and the
+     * parts of the sprite past the edges are cut off; otherwise the whole sprite wraps
+     * around.
+     *
+     * Returns true if any pixel was switched off. */
+    pub fn draw_sprite(
+        &mut self,
+        sprite: &[u8],
+        sprite_width: usize,
+        plane: u8,
+        coords: (usize, usize),
+        clip: bool,
+    ) -> bool {
+        let (w, h) = self.size();
+        let mask = self.row_mask();
+        let (start_x, start_y) = (coords.0 % w, coords.1 % h);
+        let rows = &mut self.rows[plane as usize - 1];
+        let mut erased = false;
+
+        for (y, bytes) in (start_y..).zip(sprite.chunks(sprite_width / 8)) {
+            if clip && y >= h {
+                break;
+            }
+
+            let bits = bytes
+                .iter()
+                .fold(0, |acc, &byte| (acc << 8) | u128::from(byte));
+            let sprite_row = bits << (128 - sprite_width);
+            let placed = sprite_row >> start_x;
+            let mut row = placed & mask;
+
+            if !clip {
+                // Pixels past the right edge wrap around to the left
+                row |= (placed & !mask).checked_shl(w as u32).unwrap_or(0);
+                row |= sprite_row.checked_shl(128 - start_x as u32).unwrap_or(0);
+            }
+
+            let target = &mut rows[y % h];
+            erased |= *target & row != 0;
+            *target ^= row;
+        }
+
+        erased
+    }
+
+    // Indexes of the selected planes
+    fn selected(&self) -> impl Iterator<Item = usize> {
+        let planes = self.planes;
+        (0..PLANE_COUNT).filter(move |plane| planes & (1 << plane) != 0)
+    }
+
+    // The bits of a row that are on screen
+    fn row_mask(&self) -> u128 {
+        let (w, _) = self.size();
+        !0 << (128 - w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The lit pixels of plane 1, in reading order
+    fn lit(display: &Display) -> Vec<(usize, usize)> {
+        let (w, h) = display.size();
+        (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .filter(|&(x, y)| display.pixel(x, y) & 1 != 0)
+            .collect()
+    }
+
+    fn display(hires: bool) -> Display {
+        let mut display = Display::new();
+        display.set_hires(hires);
+        display
+    }
+
+    #[test]
+    fn sprites_wrap_or_clip_at_the_edges() {
+        for &hires in [false, true].iter() {
+            let (w, h) = Display::resolution(hires);
+            // Two rows with the first and last pixel lit, one pixel from the bottom right
+            let sprite = [0x81, 0x81];
+            let coords = (w - 2, h - 1);
+
+            let mut wrapped = display(hires);
+            assert!(!wrapped.draw_sprite(&sprite, 8, 1, coords, false));
+            assert_eq!(
+                lit(&wrapped),
+                vec![(5, 0), (w - 2, 0), (5, h - 1), (w - 2, h - 1)]
+            );
+
+            let mut clipped = display(hires);
+            assert!(!clipped.draw_sprite(&sprite, 8, 1, coords, true));
+            assert_eq!(lit(&clipped), vec![(w - 2, h - 1)]);
+
+            // The starting position wraps even when clipping
+            let mut clipped = display(hires);
+            clipped.draw_sprite(&sprite, 8, 1, (w + 1, h + 2), true);
+            assert_eq!(lit(&clipped), vec![(1, 2), (8, 2), (1, 3), (8, 3)]);
+        }
+    }
+
+    #[test]
+    fn wide_sprites_wrap_or_clip_in_high_resolution() {
+        let sprite: Vec<u8> = (0..16).flat_map(|_| [0x80, 0x01].to_vec()).collect();
+        let (w, h) = HIRES_SIZE;
+
+        let mut wrapped = display(true);
+        wrapped.draw_sprite(&sprite, 16, 1, (w - 8, h - 8), false);
+        let pixels = lit(&wrapped);
+        assert_eq!(pixels.len(), 32);
+        assert!(pixels.contains(&(w - 8, h - 8)) && pixels.contains(&(7, 7)));
+        assert!(pixels.contains(&(w - 8, 7)) && pixels.contains(&(7, h - 8)));
+
+        let mut clipped = display(true);
+        clipped.draw_sprite(&sprite, 16, 1, (w - 8, h - 8), true);
+        assert_eq!(
+            lit(&clipped),
+            (h - 8..h).map(|y| (w - 8, y)).collect::<Vec<_>>()
+        );
+
+        // Lined up with the left and right edges, nothing is cut off either way
+        let mut edge = display(true);
+        edge.draw_sprite(&sprite, 16, 1, (w - 16, 0), true);
+        assert_eq!(lit(&edge).len(), 32);
+        assert_eq!(edge.pixel(w - 1, 15), 1);
+    }
+
+    #[test]
+    fn drawing_twice_erases() {
+        let mut display = display(false);
+        assert!(!display.draw_sprite(&[0xF0], 8, 1, (60, 0), false));
+        assert!(display.draw_sprite(&[0x10], 8, 1, (60, 0), false));
+        assert_eq!(lit(&display), vec![(60, 0), (61, 0), (62, 0)]);
+
+        // Erasing on the other plane doesn't count
+        assert!(!display.draw_sprite(&[0xF0], 8, 2, (60, 0), false));
+        assert_eq!(display.pixel(60, 0), 3);
+        assert_eq!(display.pixel(63, 0), 2);
+    }
+
+    // A pixel in each corner of the screen
+    fn corners(hires: bool) -> Display {
+        let (w, h) = Display::resolution(hires);
+        let mut display = display(hires);
+        for &(x, y) in [(0, 0), (w - 1, 0), (0, h - 1), (w - 1, h - 1)].iter() {
+            display.draw_sprite(&[0x80], 8, 1, (x, y), true);
+        }
+        display
+    }
+
+    #[test]
+    fn scrolling_moves_and_clears() {
+        for &hires in [false, true].iter() {
+            let (w, h) = Display::resolution(hires);
+
+            // SCD 3
+            let mut down = corners(hires);
+            down.scroll((0, 3));
+            assert_eq!(lit(&down), vec![(0, 3), (w - 1, 3)]);
+
+            // SCR
+            let mut right = corners(hires);
+            right.scroll((4, 0));
+            assert_eq!(lit(&right), vec![(4, 0), (4, h - 1)]);
+
+            // SCL
+            let mut left = corners(hires);
+            left.scroll((-4, 0));
+            assert_eq!(lit(&left), vec![(w - 5, 0), (w - 5, h - 1)]);
+
+            // SCU 1
+            let mut up = corners(hires);
+            up.scroll((0, -1));
+            assert_eq!(lit(&up), vec![(0, h - 2), (w - 1, h - 2)]);
+        }
+    }
+
+    #[test]
+    fn only_selected_planes_change() {
+        let mut display = display(false);
+        display.draw_sprite(&[0x80], 8, 1, (0, 0), false);
+        display.draw_sprite(&[0x80], 8, 2, (0, 0), false);
+
+        display.select_planes(2);
+        display.scroll((1, 0));
+        assert_eq!((display.pixel(0, 0), display.pixel(1, 0)), (1, 2));
+
+        display.clear();
+        assert_eq!((display.pixel(0, 0), display.pixel(1, 0)), (1, 0));
+
+        display.select_planes(3);
+        display.clear();
+        assert_eq!(display.pixel(0, 0), 0);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        for &hires in [false, true].iter() {
+            let (w, h) = Display::resolution(hires);
+            let mut display = display(hires);
+            display.select_planes(3);
+            display.draw_sprite(&[0xA5, 0x5A], 16, 1, (w - 5, h - 1), false);
+            display.draw_sprite(&[0x3C], 8, 2, (3, 4), false);
+
+            let bytes = display.to_bytes();
+            assert_eq!(bytes.len(), 2 * h * w / 8);
+
+            let restored = Display::from_bytes(hires, 3, &bytes).unwrap();
+            assert_eq!(restored.to_bytes(), bytes);
+            assert_eq!(restored.planes(), 3);
+            assert!(Display::from_bytes(hires, 3, &bytes[1..]).is_none());
+            assert!(Display::from_bytes(hires, 4, &bytes).is_none());
+        }
+    }
+}
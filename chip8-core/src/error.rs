@@ -0,0 +1,147 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/* Errors that stop emulation. pc is the address of the instruction that caused the
+ * error; the machine is left halted with the program counter pointing at it. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Chip8Error {
+    // RET with nothing on the stack
+    StackUnderflow { pc: usize },
+    // CALL with the stack already holding STACK_SIZE return addresses
+    StackOverflow { pc: usize },
+    // An instruction tried to read or write memory past the end of RAM
+    MemoryOutOfRange { pc: usize, address: usize },
+    UnknownOpcode { pc: usize, op_code: usize },
+    // The program counter left memory, usually after a jump to a bad address
+    PcOutOfRange { pc: usize },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::StackUnderflow { pc } => {
+                write!(
+                    f,
+                    "stack underflow: return with an empty stack at {:#05X}",
+                    pc
+                )
+            }
+            Chip8Error::StackOverflow { pc } => {
+                write!(f, "stack overflow: too many nested calls at {:#05X}", pc)
+            }
+            Chip8Error::MemoryOutOfRange { pc, address } => write!(
+                f,
+                "memory access out of range: {:#06X} accessed at {:#05X}",
+                address, pc
+            ),
+            Chip8Error::UnknownOpcode { pc, op_code } => {
+                write!(f, "unknown opcode {:04X} at {:#05X}", op_code, pc)
+            }
+            Chip8Error::PcOutOfRange { pc } => {
+                write!(f, "program counter out of range: {:#06X}", pc)
+            }
+        }
+    }
+}
+
+impl Error for Chip8Error {}
+
+// Errors from loading a ROM into memory
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    Empty,
+    // The ROM doesn't fit between the load address and the end of memory
+    TooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(error) => write!(f, "couldn't read ROM: {}", error),
+            RomError::Empty => write!(f, "ROM is empty"),
+            RomError::TooLarge { size, max } => write!(
+                f,
+                "ROM is too large: {} bytes, but only {} bytes fit in memory",
+                size, max
+            ),
+        }
+    }
+}
+
+impl Error for RomError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RomError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(error: io::Error) -> RomError {
+        RomError::Io(error)
+    }
+}
+
+// Errors from restoring a save state
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateError {
+    // The data doesn't start with the save state magic bytes
+    BadMagic,
+    // Saved by a different version of the emulator
+    UnsupportedVersion { version: u32 },
+    // The data ends before the state does
+    Truncated,
+    // A value in the state is impossible, e.g. a stack deeper than the machine allows
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion { version } => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Corrupt => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+// Errors from reading a movie or setting up a machine to play it back
+#[derive(Debug)]
+pub enum MovieError {
+    // line is 0 when the problem isn't with a particular line
+    Parse { line: usize, message: String },
+    // The movie was recorded with a different ROM
+    RomMismatch { expected: u64, found: u64 },
+    Rom(RomError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            MovieError::RomMismatch { expected, found } => write!(
+                f,
+                "movie was recorded with a different ROM (hash {:016x}, this ROM is {:016x})",
+                expected, found
+            ),
+            MovieError::Rom(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for MovieError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MovieError::Rom(error) => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,529 @@
+use crate::debugger::{Debugger, Stop};
+use crate::{Chip8, Chip8Error};
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/* A server for the GDB remote serial protocol, so that gdb and other debugger frontends
+ * can control the machine over TCP.
+ *
+ * The server never blocks: the frontend calls poll every update to accept a connection
+ * and handle whatever packets have arrived, and passes on any stop from the Debugger
+ * with stopped. Breakpoints, watchpoints and stepping all go through the Debugger, so
+ * they work the same as with its own command prompt.
+ *
+ * Registers are sent in this order, multi-byte values big endian like the CHIP-8:
+ * V0-VF (1 byte each), I (2 bytes), PC (2 bytes), SP, DT and ST (1 byte each). gdb
+ * gets the same layout from the target description, target.xml. */
+pub struct GdbServer {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    // Bytes received that don't make up a whole packet yet
+    input: Vec<u8>,
+    // Set after a continue, when gdb is waiting to hear why the machine stopped
+    running: bool,
+}
+
+// The size in bytes of each register, in the order of the g packet
+const REGISTER_SIZES: [usize; 21] = [
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 1, 1, 1,
+];
+const I_REGISTER: usize = 16;
+const PC_REGISTER: usize = 17;
+const SP_REGISTER: usize = 18;
+const DT_REGISTER: usize = 19;
+const ST_REGISTER: usize = 20;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+impl GdbServer {
+    // Listen for gdb on an address such as "127.0.0.1:1234"
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<GdbServer> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(GdbServer {
+            listener,
+            client: None,
+            input: Vec::new(),
+            running: false,
+        })
+    }
+
+    pub fn connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /* Accept a connection if there isn't one, and handle any packets that have arrived.
+     * A client that disconnects or errors is dropped and the machine is left running. */
+    pub fn poll(&mut self, system: &mut Chip8, debugger: &mut Debugger) -> io::Result<()> {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    stream.set_nodelay(true)?;
+                    self.client = Some(stream);
+                    self.input.clear();
+                    self.running = false;
+
+                    // gdb expects the machine to be stopped when it attaches
+                    debugger.pause();
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(error),
+            }
+        }
+
+        if let Err(error) = self.receive() {
+            self.disconnect(debugger);
+            return Err(error);
+        }
+
+        while let Some(packet) = self.next_packet() {
+            let result = match packet {
+                Packet::Interrupt => {
+                    debugger.pause();
+                    self.running = false;
+                    self.send("S02")
+                }
+                Packet::Command(command) => self.handle(&command, system, debugger),
+            };
+
+            if let Err(error) = result {
+                self.disconnect(debugger);
+                return Err(error);
+            }
+
+            if self.client.is_none() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tell gdb why the machine stopped, if it's waiting to hear
+    pub fn stopped(&mut self, stop: &Stop) -> io::Result<()> {
+        if !self.running || self.client.is_none() {
+            return Ok(());
+        }
+
+        self.running = false;
+        self.send(&stop_reply(stop))
+    }
+
+    fn disconnect(&mut self, debugger: &mut Debugger) {
+        self.client = None;
+        self.running = false;
+        debugger.pause();
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        let client = match &mut self.client {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+        let mut buffer = [0; 1024];
+
+        loop {
+            match client.read(&mut buffer) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::ConnectionAborted, "closed")),
+                Ok(len) => self.input.extend_from_slice(&buffer[..len]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /* Take the next packet out of the input, acknowledging it. Packets look like
+     * $data#checksum, and a lone 0x03 byte asks for the machine to be interrupted. */
+    fn next_packet(&mut self) -> Option<Packet> {
+        loop {
+            let start = self.input.iter().position(|&b| b == b'$' || b == 0x03)?;
+
+            if self.input[start] == 0x03 {
+                self.input.drain(..=start);
+                return Some(Packet::Interrupt);
+            }
+
+            let end = self.input[start..].iter().position(|&b| b == b'#')? + start;
+            if self.input.len() < end + 3 {
+                return None;
+            }
+
+            let data = self.input[(start + 1)..end].to_vec();
+            let checksum = std::str::from_utf8(&self.input[(end + 1)..(end + 3)])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            self.input.drain(..(end + 3));
+
+            let valid = checksum == Some(data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+            let ack = if valid { b"+" } else { b"-" };
+            if let Some(client) = &mut self.client {
+                write_blocking(client, ack).ok()?;
+            }
+
+            if valid {
+                return Some(Packet::Command(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+
+        match &mut self.client {
+            Some(client) => write_blocking(client, packet.as_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    fn handle(
+        &mut self,
+        command: &str,
+        system: &mut Chip8,
+        debugger: &mut Debugger,
+    ) -> io::Result<()> {
+        let reply = match command.as_bytes().first() {
+            Some(b'?') => "S05".to_string(),
+            Some(b'g') => read_registers(system),
+            Some(b'G') => ok_or_error(write_registers(system, &command[1..])),
+            Some(b'p') => match usize::from_str_radix(&command[1..], 16) {
+                Ok(register) if register < REGISTER_SIZES.len() => {
+                    encode_register(system, register)
+                }
+                _ => "E01".to_string(),
+            },
+            Some(b'P') => ok_or_error(write_register(system, &command[1..])),
+            Some(b'm') => read_memory(system, &command[1..]).unwrap_or_else(|| "E01".to_string()),
+            Some(b'M') => ok_or_error(write_memory(system, &command[1..])),
+            Some(b'c') => {
+                if let Some(address) = parse_hex(&command[1..]) {
+                    system.set_pc(address);
+                }
+                debugger.resume(system);
+                self.running = true;
+
+                // The reply is sent once the machine stops
+                return Ok(());
+            }
+            Some(b's') => {
+                if let Some(address) = parse_hex(&command[1..]) {
+                    system.set_pc(address);
+                }
+                stop_reply(&debugger.step_instruction(system))
+            }
+            Some(b'Z') | Some(b'z') => set_point(command, system, debugger),
+            Some(b'D') => {
+                self.send("OK")?;
+                self.client = None;
+                self.running = false;
+                debugger.resume(system);
+                return Ok(());
+            }
+            Some(b'k') => {
+                self.client = None;
+                self.running = false;
+                debugger.pause();
+                return Ok(());
+            }
+            Some(b'H') => "OK".to_string(),
+            _ => query_reply(command),
+        };
+
+        self.send(&reply)
+    }
+}
+
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+fn query_reply(command: &str) -> String {
+    if command.starts_with("qSupported") {
+        return "PacketSize=1000;qXfer:features:read+".to_string();
+    }
+
+    if let Some(range) = command.strip_prefix("qXfer:features:read:target.xml:") {
+        let mut numbers = range.split(',').filter_map(parse_hex);
+        return match (numbers.next(), numbers.next()) {
+            (Some(offset), Some(len)) => {
+                let xml = TARGET_XML.as_bytes();
+                let start = offset.min(xml.len());
+                let end = (start + len).min(xml.len());
+                let more = if end < xml.len() { "m" } else { "l" };
+
+                format!("{}{}", more, String::from_utf8_lossy(&xml[start..end]))
+            }
+            _ => "E01".to_string(),
+        };
+    }
+
+    match command {
+        "qAttached" => "1".to_string(),
+        "qC" => "QC1".to_string(),
+        "qfThreadInfo" => "m1".to_string(),
+        "qsThreadInfo" => "l".to_string(),
+        // An empty reply tells gdb the packet isn't supported
+        _ => String::new(),
+    }
+}
+
+fn stop_reply(stop: &Stop) -> String {
+    match stop {
+        Stop::Watchpoint { address, write, .. } => {
+            let kind = if *write { "watch" } else { "rwatch" };
+            format!("T05{}:{:x};", kind, address)
+        }
+        Stop::Exited => "W00".to_string(),
+        Stop::Error(Chip8Error::UnknownOpcode { .. }) => "S04".to_string(),
+        Stop::Error(_) => "S0b".to_string(),
+        _ => "S05".to_string(),
+    }
+}
+
+fn ok_or_error(result: Option<()>) -> String {
+    match result {
+        Some(()) => "OK".to_string(),
+        None => "E01".to_string(),
+    }
+}
+
+fn register_value(system: &Chip8, register: usize) -> usize {
+    match register {
+        I_REGISTER => system.index(),
+        PC_REGISTER => system.pc(),
+        SP_REGISTER => system.stack().len(),
+        DT_REGISTER => system.delay_timer() as usize,
+        ST_REGISTER => system.sound_timer() as usize,
+        x => system.register(x) as usize,
+    }
+}
+
+// Returns None if the value is too wide for the register
+fn set_register_value(system: &mut Chip8, register: usize, value: usize) -> Option<()> {
+    if value >> (8 * REGISTER_SIZES[register]) != 0 {
+        return None;
+    }
+
+    match register {
+        I_REGISTER => system.set_index(value),
+        PC_REGISTER => system.set_pc(value),
+        // The stack pointer follows from the return addresses, so it can't be changed
+        SP_REGISTER => {}
+        DT_REGISTER => system.set_delay_timer(value as u8),
+        ST_REGISTER => system.set_sound_timer(value as u8),
+        x => system.set_register(x, value as u8),
+    }
+
+    Some(())
+}
+
+fn encode_register(system: &Chip8, register: usize) -> String {
+    let size = REGISTER_SIZES[register];
+    let value = register_value(system, register);
+
+    format!(
+        "{:0width$x}",
+        value & ((1 << (8 * size)) - 1),
+        width = 2 * size
+    )
+}
+
+fn read_registers(system: &Chip8) -> String {
+    (0..REGISTER_SIZES.len())
+        .map(|register| encode_register(system, register))
+        .collect()
+}
+
+fn write_registers(system: &mut Chip8, hex: &str) -> Option<()> {
+    let mut position = 0;
+
+    for (register, size) in REGISTER_SIZES.iter().enumerate() {
+        let value = parse_hex(hex.get(position..(position + 2 * size))?)?;
+        set_register_value(system, register, value)?;
+        position += 2 * size;
+    }
+
+    Some(())
+}
+
+// P<register>=<value>
+fn write_register(system: &mut Chip8, args: &str) -> Option<()> {
+    let (register, value) = args.split_once('=')?;
+    let register = parse_hex(register).filter(|&r| r < REGISTER_SIZES.len())?;
+
+    set_register_value(system, register, parse_hex(value)?)
+}
+
+// m<address>,<length>
+fn read_memory(system: &Chip8, args: &str) -> Option<String> {
+    let (address, len) = args.split_once(',')?;
+    let (address, len) = (parse_hex(address)?, parse_hex(len)?);
+    let bytes = system.memory().get(address..address.checked_add(len)?)?;
+
+    let mut hex = String::new();
+    for byte in bytes.iter() {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+
+    Some(hex)
+}
+
+// M<address>,<length>:<bytes>
+fn write_memory(system: &mut Chip8, args: &str) -> Option<()> {
+    let (range, data) = args.split_once(':')?;
+    let (address, len) = range.split_once(',')?;
+    let (address, len) = (parse_hex(address)?, parse_hex(len)?);
+
+    if data.len() != 2 * len {
+        return None;
+    }
+
+    let memory = system
+        .memory_mut()
+        .get_mut(address..address.checked_add(len)?)?;
+    for (i, byte) in memory.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(data.get((2 * i)..(2 * i + 2))?, 16).ok()?;
+    }
+
+    Some(())
+}
+
+/* Z<type>,<address>,<kind> sets and z removes a breakpoint or watchpoint. Types 0 and 1
+ * are breakpoints; 2, 3 and 4 watch for writes, reads and both. For watchpoints kind
+ * is the number of bytes to watch, which must all be in memory. Point types we don't
+ * know get an empty reply, so gdb stops asking for them. */
+fn set_point(command: &str, system: &Chip8, debugger: &mut Debugger) -> String {
+    let insert = command.starts_with('Z');
+    let mut args = command[1..].split(',');
+    let point_type = args.next().unwrap_or("");
+    let (address, len) = match (
+        args.next().and_then(parse_hex),
+        args.next().and_then(parse_hex),
+    ) {
+        (Some(address), Some(len)) => (address, len),
+        _ => return "E01".to_string(),
+    };
+
+    let (read, write) = match point_type {
+        "0" | "1" => {
+            if insert {
+                debugger.add_breakpoint(address);
+            } else {
+                debugger.remove_breakpoint(address);
+            }
+            return "OK".to_string();
+        }
+        "2" => (false, true),
+        "3" => (true, false),
+        "4" => (true, true),
+        _ => return String::new(),
+    };
+
+    if insert {
+        let in_memory = address
+            .checked_add(len)
+            .is_some_and(|end| end <= system.memory().len());
+        if !in_memory {
+            return "E01".to_string();
+        }
+
+        debugger.add_watchpoint(address, len, read, write);
+    } else {
+        debugger.remove_watchpoint(address);
+    }
+
+    "OK".to_string()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+// Replies are small, so it's simplest to wait for them to be written
+fn write_blocking(stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let result = stream.write_all(data);
+    stream.set_nonblocking(true)?;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Quirks;
+
+    #[test]
+    fn watchpoints_must_be_in_memory() {
+        let system = Chip8::with_seed(600, Quirks::default(), 0);
+        let mut debugger = Debugger::new();
+
+        assert_eq!(set_point("Z2,300,2", &system, &mut debugger), "OK");
+        assert_eq!(set_point("Z4,ffe,2", &system, &mut debugger), "OK");
+        assert_eq!(set_point("Z3,fff,2", &system, &mut debugger), "E01");
+        assert_eq!(
+            set_point("Z2,300,ffffffffffffffff", &system, &mut debugger),
+            "E01"
+        );
+        assert_eq!(set_point("Z2,300", &system, &mut debugger), "E01");
+        assert_eq!(set_point("z2,300,2", &system, &mut debugger), "OK");
+        assert_eq!(set_point("Z0,200,2", &system, &mut debugger), "OK");
+        assert_eq!(set_point("Z5,200,2", &system, &mut debugger), "");
+    }
+
+    #[test]
+    fn registers_must_fit() {
+        let mut system = Chip8::with_seed(600, Quirks::default(), 0);
+
+        assert_eq!(write_register(&mut system, "3=ab"), Some(()));
+        assert_eq!(write_register(&mut system, "10=fff"), Some(()));
+        assert_eq!(write_register(&mut system, "11=ffff"), Some(()));
+        assert_eq!(system.register(3), 0xAB);
+        assert_eq!((system.index(), system.pc()), (0xFFF, 0xFFFF));
+
+        assert_eq!(write_register(&mut system, "3=100"), None);
+        assert_eq!(write_register(&mut system, "10=10000"), None);
+        assert_eq!(write_register(&mut system, "11=ffffffffffffffff"), None);
+        assert_eq!(write_register(&mut system, "15=1"), None);
+        assert_eq!(system.register(3), 0xAB);
+        assert_eq!((system.index(), system.pc()), (0xFFF, 0xFFFF));
+
+        // G takes every register at its own width
+        let mut registers = read_registers(&system);
+        registers.replace_range(32..40, "03000202");
+        assert_eq!(write_registers(&mut system, &registers), Some(()));
+        assert_eq!((system.index(), system.pc()), (0x300, 0x202));
+        assert_eq!(write_registers(&mut system, &registers[1..]), None);
+    }
+}
@@ -0,0 +1,463 @@
+use std::fmt;
+
+/* A decoded CHIP-8, SUPER-CHIP or XO-CHIP instruction.
+ *
+ * x and y are register numbers, byte is an 8 bit immediate and addresses are 12 bits,
+ * except for the 16 bit address of an XO-CHIP long index load. Every 16 bit opcode
+ * decodes to exactly one instruction and encodes back to the same opcode; anything that
+ * isn't a valid instruction becomes Unknown. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    // 0nnn
+    Sys(u16),
+    // 00E0
+    Cls,
+    // 00EE
+    Ret,
+    // 00Cn
+    Scd(u8),
+    // 00FB
+    Scr,
+    // 00FC
+    Scl,
+    // 00FD
+    Exit,
+    // 00FE
+    Low,
+    // 00FF
+    High,
+    // 1nnn
+    Jp(u16),
+    // 2nnn
+    Call(u16),
+    // 3xkk
+    SeByte { x: u8, byte: u8 },
+    // 4xkk
+    SneByte { x: u8, byte: u8 },
+    // 5xy0
+    SeReg { x: u8, y: u8 },
+    // 5xy2
+    Save { x: u8, y: u8 },
+    // 5xy3
+    Load { x: u8, y: u8 },
+    // 6xkk
+    LdByte { x: u8, byte: u8 },
+    // 7xkk
+    AddByte { x: u8, byte: u8 },
+    // 8xy0
+    LdReg { x: u8, y: u8 },
+    // 8xy1
+    Or { x: u8, y: u8 },
+    // 8xy2
+    And { x: u8, y: u8 },
+    // 8xy3
+    Xor { x: u8, y: u8 },
+    // 8xy4
+    AddReg { x: u8, y: u8 },
+    // 8xy5
+    Sub { x: u8, y: u8 },
+    // 8xy6
+    Shr { x: u8, y: u8 },
+    // 8xy7
+    Subn { x: u8, y: u8 },
+    // 8xyE
+    Shl { x: u8, y: u8 },
+    // 9xy0
+    SneReg { x: u8, y: u8 },
+    // Annn
+    LdI(u16),
+    // Bnnn
+    JpV0(u16),
+    // Cxkk
+    Rnd { x: u8, byte: u8 },
+    // Dxyn
+    Drw { x: u8, y: u8, n: u8 },
+    // Ex9E
+    Skp(u8),
+    // ExA1
+    Sknp(u8),
+    // F000 nnnn
+    LdILong(u16),
+    // Fn01
+    Plane(u8),
+    // F002
+    Audio,
+    // Fx07
+    LdVxDt(u8),
+    // Fx0A
+    LdVxK(u8),
+    // Fx15
+    LdDtVx(u8),
+    // Fx18
+    LdStVx(u8),
+    // Fx1E
+    AddI(u8),
+    // Fx29
+    LdF(u8),
+    // Fx30
+    LdHf(u8),
+    // Fx33
+    LdB(u8),
+    // Fx3A
+    Pitch(u8),
+    // Fx55
+    LdIVx(u8),
+    // Fx65
+    LdVxI(u8),
+    // Fx75
+    LdRVx(u8),
+    // Fx85
+    LdVxR(u8),
+    Unknown(u16),
+}
+
+impl Instruction {
+    /* Decode a single opcode. F000 nnnn is the only instruction that takes two words;
+     * decoding its first word gives LdILong(0), and the address has to be filled in from
+     * the word that follows, which Instruction::fetch does. */
+    pub fn decode(op_code: u16) -> Instruction {
+        let x = ((op_code & 0x0F00) >> 8) as u8;
+        let y = ((op_code & 0x00F0) >> 4) as u8;
+        let n = (op_code & 0x000F) as u8;
+        let byte = (op_code & 0x00FF) as u8;
+        let addr = op_code & 0x0FFF;
+
+        match op_code & 0xF000 {
+            0x0000 => match op_code {
+                0x00E0 => Instruction::Cls,
+                0x00EE => Instruction::Ret,
+                0x00FB => Instruction::Scr,
+                0x00FC => Instruction::Scl,
+                0x00FD => Instruction::Exit,
+                0x00FE => Instruction::Low,
+                0x00FF => Instruction::High,
+                _ if op_code & 0xFFF0 == 0x00C0 => Instruction::Scd(n),
+                _ => Instruction::Sys(addr),
+            },
+            0x1000 => Instruction::Jp(addr),
+            0x2000 => Instruction::Call(addr),
+            0x3000 => Instruction::SeByte { x, byte },
+            0x4000 => Instruction::SneByte { x, byte },
+            0x5000 => match n {
+                0x0 => Instruction::SeReg { x, y },
+                0x2 => Instruction::Save { x, y },
+                0x3 => Instruction::Load { x, y },
+                _ => Instruction::Unknown(op_code),
+            },
+            0x6000 => Instruction::LdByte { x, byte },
+            0x7000 => Instruction::AddByte { x, byte },
+            0x8000 => match n {
+                0x0 => Instruction::LdReg { x, y },
+                0x1 => Instruction::Or { x, y },
+                0x2 => Instruction::And { x, y },
+                0x3 => Instruction::Xor { x, y },
+                0x4 => Instruction::AddReg { x, y },
+                0x5 => Instruction::Sub { x, y },
+                0x6 => Instruction::Shr { x, y },
+                0x7 => Instruction::Subn { x, y },
+                0xE => Instruction::Shl { x, y },
+                _ => Instruction::Unknown(op_code),
+            },
+            0x9000 if n == 0 => Instruction::SneReg { x, y },
+            0xA000 => Instruction::LdI(addr),
+            0xB000 => Instruction::JpV0(addr),
+            0xC000 => Instruction::Rnd { x, byte },
+            0xD000 => Instruction::Drw { x, y, n },
+            0xE000 => match byte {
+                0x9E => Instruction::Skp(x),
+                0xA1 => Instruction::Sknp(x),
+                _ => Instruction::Unknown(op_code),
+            },
+            0xF000 => match byte {
+                0x00 if x == 0 => Instruction::LdILong(0),
+                0x01 => Instruction::Plane(x),
+                0x02 if x == 0 => Instruction::Audio,
+                0x07 => Instruction::LdVxDt(x),
+                0x0A => Instruction::LdVxK(x),
+                0x15 => Instruction::LdDtVx(x),
+                0x18 => Instruction::LdStVx(x),
+                0x1E => Instruction::AddI(x),
+                0x29 => Instruction::LdF(x),
+                0x30 => Instruction::LdHf(x),
+                0x33 => Instruction::LdB(x),
+                0x3A => Instruction::Pitch(x),
+                0x55 => Instruction::LdIVx(x),
+                0x65 => Instruction::LdVxI(x),
+                0x75 => Instruction::LdRVx(x),
+                0x85 => Instruction::LdVxR(x),
+                _ => Instruction::Unknown(op_code),
+            },
+            _ => Instruction::Unknown(op_code),
+        }
+    }
+
+    /* Decode the instruction stored at address, including the second word of a long
+     * index load. Returns None if the instruction runs past the end of memory. */
+    pub fn fetch(memory: &[u8], address: usize) -> Option<Instruction> {
+        let word = |at: usize| -> Option<u16> {
+            let high_byte = *memory.get(at)? as u16;
+            let low_byte = *memory.get(at + 1)? as u16;
+            Some((high_byte << 8) | low_byte)
+        };
+
+        match Instruction::decode(word(address)?) {
+            Instruction::LdILong(_) => Some(Instruction::LdILong(word(address + 2)?)),
+            instruction => Some(instruction),
+        }
+    }
+
+    // The first word of the instruction's encoding
+    pub fn encode(&self) -> u16 {
+        let xy = |base: u16, x: u8, y: u8, n: u16| -> u16 {
+            base | (u16::from(x & 0x0F) << 8) | (u16::from(y & 0x0F) << 4) | n
+        };
+        let xkk = |base: u16, x: u8, byte: u8| -> u16 {
+            base | (u16::from(x & 0x0F) << 8) | u16::from(byte)
+        };
+        let fx = |x: u8, low: u16| -> u16 { 0xF000 | (u16::from(x & 0x0F) << 8) | low };
+
+        match *self {
+            Instruction::Sys(addr) => addr & 0x0FFF,
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::Scd(n) => 0x00C0 | u16::from(n & 0x0F),
+            Instruction::Scr => 0x00FB,
+            Instruction::Scl => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::Low => 0x00FE,
+            Instruction::High => 0x00FF,
+            Instruction::Jp(addr) => 0x1000 | (addr & 0x0FFF),
+            Instruction::Call(addr) => 0x2000 | (addr & 0x0FFF),
+            Instruction::SeByte { x, byte } => xkk(0x3000, x, byte),
+            Instruction::SneByte { x, byte } => xkk(0x4000, x, byte),
+            Instruction::SeReg { x, y } => xy(0x5000, x, y, 0x0),
+            Instruction::Save { x, y } => xy(0x5000, x, y, 0x2),
+            Instruction::Load { x, y } => xy(0x5000, x, y, 0x3),
+            Instruction::LdByte { x, byte } => xkk(0x6000, x, byte),
+            Instruction::AddByte { x, byte } => xkk(0x7000, x, byte),
+            Instruction::LdReg { x, y } => xy(0x8000, x, y, 0x0),
+            Instruction::Or { x, y } => xy(0x8000, x, y, 0x1),
+            Instruction::And { x, y } => xy(0x8000, x, y, 0x2),
+            Instruction::Xor { x, y } => xy(0x8000, x, y, 0x3),
+            Instruction::AddReg { x, y } => xy(0x8000, x, y, 0x4),
+            Instruction::Sub { x, y } => xy(0x8000, x, y, 0x5),
+            Instruction::Shr { x, y } => xy(0x8000, x, y, 0x6),
+            Instruction::Subn { x, y } => xy(0x8000, x, y, 0x7),
+            Instruction::Shl { x, y } => xy(0x8000, x, y, 0xE),
+            Instruction::SneReg { x, y } => xy(0x9000, x, y, 0x0),
+            Instruction::LdI(addr) => 0xA000 | (addr & 0x0FFF),
+            Instruction::JpV0(addr) => 0xB000 | (addr & 0x0FFF),
+            Instruction::Rnd { x, byte } => xkk(0xC000, x, byte),
+            Instruction::Drw { x, y, n } => xy(0xD000, x, y, u16::from(n & 0x0F)),
+            Instruction::Skp(x) => xkk(0xE000, x, 0x9E),
+            Instruction::Sknp(x) => xkk(0xE000, x, 0xA1),
+            Instruction::LdILong(_) => 0xF000,
+            Instruction::Plane(n) => fx(n, 0x01),
+            Instruction::Audio => 0xF002,
+            Instruction::LdVxDt(x) => fx(x, 0x07),
+            Instruction::LdVxK(x) => fx(x, 0x0A),
+            Instruction::LdDtVx(x) => fx(x, 0x15),
+            Instruction::LdStVx(x) => fx(x, 0x18),
+            Instruction::AddI(x) => fx(x, 0x1E),
+            Instruction::LdF(x) => fx(x, 0x29),
+            Instruction::LdHf(x) => fx(x, 0x30),
+            Instruction::LdB(x) => fx(x, 0x33),
+            Instruction::Pitch(x) => fx(x, 0x3A),
+            Instruction::LdIVx(x) => fx(x, 0x55),
+            Instruction::LdVxI(x) => fx(x, 0x65),
+            Instruction::LdRVx(x) => fx(x, 0x75),
+            Instruction::LdVxR(x) => fx(x, 0x85),
+            Instruction::Unknown(op_code) => op_code,
+        }
+    }
+
+    // The full encoding, as it's stored in memory
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = self.encode().to_be_bytes().to_vec();
+
+        if let Instruction::LdILong(addr) = *self {
+            bytes.extend_from_slice(&addr.to_be_bytes());
+        }
+
+        bytes
+    }
+
+    // Size in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::LdILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    // Whether the instruction only exists in XO-CHIP mode
+    pub fn is_xo_chip(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Save { .. }
+                | Instruction::Load { .. }
+                | Instruction::LdILong(_)
+                | Instruction::Plane(_)
+                | Instruction::Audio
+                | Instruction::Pitch(_)
+        )
+    }
+}
+
+/* Instructions are shown with the usual CHIP-8 mnemonics, e.g. "DRW V1, V2, 5".
+ * Unknown opcodes are shown as a DW data directive so a listing can be reassembled. */
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Sys(addr) => write!(f, "SYS {:#05X}", addr),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Scd(n) => write!(f, "SCD {}", n),
+            Instruction::Scr => write!(f, "SCR"),
+            Instruction::Scl => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Low => write!(f, "LOW"),
+            Instruction::High => write!(f, "HIGH"),
+            Instruction::Jp(addr) => write!(f, "JP {:#05X}", addr),
+            Instruction::Call(addr) => write!(f, "CALL {:#05X}", addr),
+            Instruction::SeByte { x, byte } => write!(f, "SE V{:X}, {:#04X}", x, byte),
+            Instruction::SneByte { x, byte } => write!(f, "SNE V{:X}, {:#04X}", x, byte),
+            Instruction::SeReg { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::Save { x, y } => write!(f, "SAVE V{:X} - V{:X}", x, y),
+            Instruction::Load { x, y } => write!(f, "LOAD V{:X} - V{:X}", x, y),
+            Instruction::LdByte { x, byte } => write!(f, "LD V{:X}, {:#04X}", x, byte),
+            Instruction::AddByte { x, byte } => write!(f, "ADD V{:X}, {:#04X}", x, byte),
+            Instruction::LdReg { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(addr) => write!(f, "LD I, {:#05X}", addr),
+            Instruction::JpV0(addr) => write!(f, "JP V0, {:#05X}", addr),
+            Instruction::Rnd { x, byte } => write!(f, "RND V{:X}, {:#04X}", x, byte),
+            Instruction::Drw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdILong(addr) => write!(f, "LD I, long {:#06X}", addr),
+            Instruction::Plane(n) => write!(f, "PLANE {}", n),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdF(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdHf(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::LdB(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LdVxR(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::Unknown(op_code) => write!(f, "DW {:#06X}", op_code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_opcode_round_trips() {
+        for op_code in 0..=0xFFFF {
+            let instruction = Instruction::decode(op_code);
+            assert_eq!(instruction.encode(), op_code, "{}", instruction);
+
+            // Every word but F000 is a whole instruction on its own
+            let memory = op_code.to_be_bytes();
+            match instruction {
+                Instruction::LdILong(_) => assert_eq!(Instruction::fetch(&memory, 0), None),
+                _ => {
+                    assert_eq!(Instruction::fetch(&memory, 0), Some(instruction));
+                    assert_eq!(instruction.bytes(), memory.to_vec());
+                    assert_eq!(instruction.size(), 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn long_index_loads_take_two_words() {
+        let memory = [0x00, 0xF0, 0x00, 0x12, 0x34];
+        let instruction = Instruction::fetch(&memory, 1).unwrap();
+        assert_eq!(instruction, Instruction::LdILong(0x1234));
+        assert_eq!(instruction.bytes(), memory[1..].to_vec());
+        assert_eq!((instruction.size(), instruction.encode()), (4, 0xF000));
+        assert_eq!(Instruction::fetch(&memory, 4), None);
+
+        // Only F000 itself: the other F_00 words are unknown
+        assert_eq!(Instruction::decode(0xF100), Instruction::Unknown(0xF100));
+    }
+
+    #[test]
+    fn only_xo_chip_instructions_need_xo_chip() {
+        let xo_chip: Vec<u16> = (0..=0xFFFF)
+            .filter(|&op_code| Instruction::decode(op_code).is_xo_chip())
+            .collect();
+
+        for &op_code in xo_chip.iter() {
+            let kind = (op_code & 0xF00F, op_code & 0xF0FF);
+            assert!(
+                matches!(kind, (0x5002, _) | (0x5003, _) | (_, 0xF001) | (_, 0xF03A))
+                    || op_code == 0xF000
+                    || op_code == 0xF002,
+                "{:04X}",
+                op_code
+            );
+        }
+        // SAVE, LOAD, PLANE and PITCH for every register, plus F000 and AUDIO
+        assert_eq!(xo_chip.len(), 256 * 2 + 16 * 2 + 2);
+    }
+
+    #[test]
+    fn instructions_display_as_assembly() {
+        let listing: Vec<String> = [
+            0x00E0, 0x00C3, 0x1234, 0x2ABC, 0x3A0F, 0x5122, 0x5123, 0x8AB6, 0xB300, 0xC1FF, 0xD12F,
+            0xE3A1, 0xF202, 0xF801, 0xF33A, 0xFC55, 0xF085, 0x0123, 0x5124, 0xFFFF,
+        ]
+        .iter()
+        .map(|&op_code| Instruction::decode(op_code).to_string())
+        .collect();
+
+        assert_eq!(
+            listing,
+            vec![
+                "CLS",
+                "SCD 3",
+                "JP 0x234",
+                "CALL 0xABC",
+                "SE VA, 0x0F",
+                "SAVE V1 - V2",
+                "LOAD V1 - V2",
+                "SHR VA, VB",
+                "JP V0, 0x300",
+                "RND V1, 0xFF",
+                "DRW V1, V2, 15",
+                "SKNP V3",
+                "DW 0xF202",
+                "PLANE 8",
+                "PITCH V3",
+                "LD [I], VC",
+                "LD V0, R",
+                "SYS 0x123",
+                "DW 0x5124",
+                "DW 0xFFFF",
+            ]
+        );
+        assert_eq!(
+            Instruction::LdILong(0xBEEF).to_string(),
+            "LD I, long 0xBEEF"
+        );
+    }
+}
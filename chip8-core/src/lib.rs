@@ -0,0 +1,1185 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+pub mod asm;
+pub mod audio;
+pub mod cli;
+pub mod debugger;
+pub mod disasm;
+mod display;
+mod error;
+pub mod gdb;
+mod instruction;
+pub mod movie;
+pub mod palette;
+mod png;
+mod quirks;
+mod render;
+mod rewind;
+mod rng;
+mod state;
+mod trace;
+pub use error::{Chip8Error, MovieError, RomError, StateError};
+pub use instruction::Instruction;
+pub use quirks::Quirks;
+pub use render::Renderer;
+pub use rewind::Rewind;
+pub use trace::Tracer;
+
+use display::Display;
+use rng::Rng;
+
+const FONT: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, 0x20, 0x60, 0x20, 0x20, 0x70, 0xF0, 0x10, 0xF0, 0x80, 0xF0, 0xF0,
+    0x10, 0xF0, 0x10, 0xF0, 0x90, 0x90, 0xF0, 0x10, 0x10, 0xF0, 0x80, 0xF0, 0x10, 0xF0, 0xF0, 0x80,
+    0xF0, 0x90, 0xF0, 0xF0, 0x10, 0x20, 0x40, 0x40, 0xF0, 0x90, 0xF0, 0x90, 0xF0, 0xF0, 0x90, 0xF0,
+    0x10, 0xF0, 0xF0, 0x90, 0xF0, 0x90, 0x90, 0xE0, 0x90, 0xE0, 0x90, 0xE0, 0xF0, 0x80, 0x80, 0x80,
+    0xF0, 0xE0, 0x90, 0x90, 0x90, 0xE0, 0xF0, 0x80, 0xF0, 0x80, 0xF0, 0xF0, 0x80, 0xF0, 0x80, 0x80,
+];
+
+// SUPER-CHIP's 8x10 digit font, extended to A-F the same way XO-CHIP does
+const BIG_FONT: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x18, 0x78, 0x78, 0x18, 0x18, 0x18,
+    0x18, 0x18, 0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF,
+    0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03,
+    0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xFF, 0xFF, 0xC0, 0xC0,
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18,
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,
+    0x03, 0x03, 0xFF, 0xFF, 0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xFC, 0xFC,
+    0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3,
+    0xFF, 0x3C, 0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, 0xFF, 0xFF, 0xC0, 0xC0,
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0,
+];
+
+// The big font is stored in memory right after the regular font
+const BIG_FONT_ADDRESS: usize = 0x50;
+
+// Where ROMs are loaded and execution starts unless set_load_address says otherwise
+pub const DEFAULT_LOAD_ADDRESS: usize = 0x200;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+// Maximum number of nested subroutine calls
+const STACK_SIZE: usize = 16;
+
+// Memory sizes for CHIP-8 and XO-CHIP programs
+const MEMORY_SIZE: usize = 0x1000;
+const XO_CHIP_MEMORY_SIZE: usize = 0x10000;
+
+pub struct Chip8 {
+    io: IOState,
+    cpu: CpuState,
+    quirks: Quirks,
+    tracer: Option<Tracer>,
+}
+
+struct IOState {
+    key_inputs: [u8; 16],
+    display: Display,
+    memory: Vec<u8>,
+    stack: Vec<usize>,
+    rpl_flags: [u8; 16],
+    audio_pattern: [u8; 16],
+    pitch: u8,
+    xo_chip: bool,
+}
+
+struct CpuState {
+    registers: [usize; 16],
+    pc: usize,
+    load_address: usize,
+    index: usize,
+    sound_timer: usize,
+    delay_timer: usize,
+    waiting: bool,
+    vblank_wait: bool,
+    exited: bool,
+    halted: Option<Chip8Error>,
+    clock_speed: usize,
+    cycles: u64,
+    frames: u64,
+    /* Emulated time in 60 * clock_speed ticks a second, so that cycles start every 60
+     * ticks and the timers count down every clock_speed ticks */
+    time: u64,
+    // Leftover time from run_for, in nanoseconds multiplied by 60 * clock_speed
+    time_carry: u128,
+    rng: Rng,
+}
+
+impl Chip8 {
+    // A machine with a random seed, so RND gives different numbers every run
+    pub fn new(clock_speed: usize, quirks: Quirks) -> Chip8 {
+        Chip8::with_rng(clock_speed, quirks, Rng::from_time())
+    }
+
+    // A machine whose RND results are the same every time it's given the same seed
+    pub fn with_seed(clock_speed: usize, quirks: Quirks, seed: u64) -> Chip8 {
+        Chip8::with_rng(clock_speed, quirks, Rng::new(seed))
+    }
+
+    fn with_rng(clock_speed: usize, quirks: Quirks, rng: Rng) -> Chip8 {
+        // Without cycles there'd be no time for the timers to count down in
+        assert!(clock_speed > 0, "clock speed must be at least 1 Hz");
+
+        let mut io = IOState {
+            key_inputs: [0; 16],
+            display: Display::new(),
+            memory: vec![0; MEMORY_SIZE],
+            stack: Vec::new(),
+            rpl_flags: [0; 16],
+            audio_pattern: [0; 16],
+            pitch: 64,
+            xo_chip: false,
+        };
+
+        let cpu = CpuState {
+            registers: [0; 16],
+            pc: DEFAULT_LOAD_ADDRESS,
+            load_address: DEFAULT_LOAD_ADDRESS,
+            index: 0,
+            sound_timer: 0,
+            delay_timer: 0,
+            waiting: false,
+            vblank_wait: false,
+            exited: false,
+            halted: None,
+            clock_speed,
+            cycles: 0,
+            frames: 0,
+            time: 0,
+            time_carry: 0,
+            rng,
+        };
+
+        // Load fonts
+        for (i, ch) in FONT.iter().enumerate() {
+            io.memory[i] = *ch;
+        }
+
+        for (i, ch) in BIG_FONT.iter().enumerate() {
+            io.memory[BIG_FONT_ADDRESS + i] = *ch;
+        }
+
+        Chip8 {
+            io,
+            cpu,
+            quirks,
+            tracer: None,
+        }
+    }
+
+    /* Set where ROMs get loaded and where execution starts, e.g. 0x600 for ETI 660
+     * programs. Call this before loading a ROM. */
+    pub fn set_load_address(&mut self, address: usize) {
+        self.cpu.load_address = address;
+        self.cpu.pc = address;
+    }
+
+    pub fn load_address(&self) -> usize {
+        self.cpu.load_address
+    }
+
+    // Start logging every instruction executed
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    // Stop tracing, handing back the tracer so it can be finished
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    // Load a ROM from a file. Returns the number of bytes loaded.
+    pub fn load_rom(&mut self, path_string: &str) -> Result<usize, RomError> {
+        let path = Path::new(path_string);
+        let rom = File::open(path)?;
+
+        self.load_rom_reader(rom)
+    }
+
+    // Load a ROM from anything that can be read. Returns the number of bytes loaded.
+    pub fn load_rom_reader<R: Read>(&mut self, mut reader: R) -> Result<usize, RomError> {
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer)?;
+
+        self.load_rom_bytes(&buffer)
+    }
+
+    /* Copy a ROM into memory at the load address. Returns the number of bytes loaded.
+     *
+     * XO-CHIP ROMs can be larger than regular ones, so enable XO-CHIP mode first when
+     * loading one. */
+    pub fn load_rom_bytes(&mut self, rom: &[u8]) -> Result<usize, RomError> {
+        let start = self.cpu.load_address;
+        let max = self.io.memory.len().saturating_sub(start);
+
+        if rom.is_empty() {
+            return Err(RomError::Empty);
+        }
+
+        if rom.len() > max {
+            return Err(RomError::TooLarge {
+                size: rom.len(),
+                max,
+            });
+        }
+
+        self.io.memory[start..(start + rom.len())].copy_from_slice(rom);
+
+        Ok(rom.len())
+    }
+
+    /* Advance the machine by an amount of emulated time, e.g. the wall-clock time since
+     * the last frame was drawn. Instructions run at clock_speed per second and the
+     * timers count down at exactly 60 Hz, however slow the clock. Time left over from a
+     * fraction of a tick is carried over to the next call.
+     *
+     * If an instruction fails, the machine halts with the program counter on that
+     * instruction and every later call returns the same error. */
+    pub fn run_for(&mut self, elapsed: Duration) -> Result<(), Chip8Error> {
+        self.run_for_with(elapsed, Chip8::step)
+    }
+
+    // Run until the timers next count down, a 60th of a second after they last did
+    pub fn run_frame(&mut self) -> Result<(), Chip8Error> {
+        self.run_frame_with(Chip8::step)
+    }
+
+    pub fn run_cycles(&mut self, count: u64) -> Result<(), Chip8Error> {
+        for _ in 0..count {
+            self.step()?;
+        }
+
+        Ok(())
+    }
+
+    /* run_for and run_frame, with every cycle run by step_cycle instead of step so the
+     * debugger can check for breakpoints in between. step_cycle must call step, and
+     * can stop the run early by returning an error. */
+    pub(crate) fn run_for_with<E>(
+        &mut self,
+        elapsed: Duration,
+        step_cycle: impl FnMut(&mut Chip8) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let ticks_per_second = 60 * self.cpu.clock_speed as u128;
+        let total = elapsed.as_nanos() * ticks_per_second + self.cpu.time_carry;
+
+        self.cpu.time_carry = total % NANOS_PER_SECOND;
+        let until = self.cpu.time + (total / NANOS_PER_SECOND) as u64;
+        self.run_until(until, step_cycle)
+    }
+
+    pub(crate) fn run_frame_with<E>(
+        &mut self,
+        step_cycle: impl FnMut(&mut Chip8) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let until = (self.cpu.frames + 1) * self.cpu.clock_speed as u64;
+        self.run_until(until, step_cycle)
+    }
+
+    // Run every cycle that starts before a point in emulated time, then move time on to it
+    fn run_until<E>(
+        &mut self,
+        until: u64,
+        mut step_cycle: impl FnMut(&mut Chip8) -> Result<(), E>,
+    ) -> Result<(), E> {
+        while self.cpu.cycles * 60 < until {
+            step_cycle(self)?;
+        }
+
+        self.cpu.time = self.cpu.time.max(until);
+        self.update_timers();
+
+        Ok(())
+    }
+
+    /* Run a single cycle. Normally that executes one instruction, but nothing happens
+     * after EXIT or while waiting for the next frame because of the display wait quirk.
+     * Before it starts, the timers catch up with any 60ths of a second that have passed. */
+    pub fn step(&mut self) -> Result<(), Chip8Error> {
+        if let Some(error) = &self.cpu.halted {
+            return Err(error.clone());
+        }
+
+        self.cpu.time = self.cpu.time.max(self.cpu.cycles * 60);
+        self.update_timers();
+
+        if self.ready() {
+            self.execute_instruction()?;
+        }
+
+        self.cpu.cycles += 1;
+
+        Ok(())
+    }
+
+    // True if the next step will execute an instruction
+    pub fn ready(&self) -> bool {
+        !self.cpu.exited && !self.cpu.vblank_wait
+    }
+
+    fn execute_instruction(&mut self) -> Result<(), Chip8Error> {
+        let pc = self.cpu.pc;
+        let instruction = match Instruction::fetch(&self.io.memory, pc) {
+            Some(instruction) => instruction,
+            None => return Err(self.halt(Chip8Error::PcOutOfRange { pc })),
+        };
+
+        if let Some(tracer) = &mut self.tracer {
+            let cycle = self.cpu.cycles;
+            tracer.trace(cycle, pc, instruction, &self.cpu.registers, self.cpu.index);
+        }
+
+        self.cpu.pc += instruction.size();
+        if let Err(error) = process_opcode(&mut self.io, &mut self.cpu, &self.quirks, instruction) {
+            self.cpu.pc = pc;
+            return Err(self.halt(error));
+        }
+
+        // The VIP waits for the next vertical blank after drawing a sprite, so
+        // nothing else executes until the next frame
+        if self.quirks.display_wait && matches!(instruction, Instruction::Drw { .. }) {
+            self.cpu.vblank_wait = true;
+        }
+
+        Ok(())
+    }
+
+    // Count the timers down once for every 60th of a second up to the current time
+    fn update_timers(&mut self) {
+        let clock_speed = self.cpu.clock_speed as u64;
+
+        while (self.cpu.frames + 1) * clock_speed <= self.cpu.time {
+            self.cpu.frames += 1;
+            self.cpu.vblank_wait = false;
+
+            if self.cpu.delay_timer > 0 {
+                self.cpu.delay_timer -= 1;
+            }
+
+            if self.cpu.sound_timer > 0 {
+                self.cpu.sound_timer -= 1;
+            }
+        }
+    }
+
+    // Number of times the 60 Hz timers have counted down since the machine started
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.frames
+    }
+
+    // Number of cycles run since the machine started
+    pub fn cycle_count(&self) -> u64 {
+        self.cpu.cycles
+    }
+
+    pub fn clock_speed(&self) -> usize {
+        self.cpu.clock_speed
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    // The seed RND's generator started from, for repeating a run with with_seed
+    pub fn seed(&self) -> u64 {
+        self.cpu.rng.seed()
+    }
+
+    // The error that stopped emulation, if any
+    pub fn halted(&self) -> Option<&Chip8Error> {
+        self.cpu.halted.as_ref()
+    }
+
+    fn halt(&mut self, error: Chip8Error) -> Chip8Error {
+        self.cpu.halted = Some(error.clone());
+        error
+    }
+
+    /* The size of the display in pixels. It changes when a SUPER-CHIP program switches
+     * between low and high resolution mode. Use pixel to read the display, or a
+     * Renderer to turn it into RGBA data for drawing. */
+    pub fn display_width(&self) -> usize {
+        self.io.display.size().0
+    }
+
+    pub fn display_height(&self) -> usize {
+        self.io.display.size().1
+    }
+
+    // The bitplanes switched on at a pixel, 0 if it's blank
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.io.display.pixel(x, y)
+    }
+
+    pub fn hires(&self) -> bool {
+        self.io.display.hires()
+    }
+
+    /* Switch to XO-CHIP mode: memory grows to 64 KiB and the XO-CHIP extensions
+     * (long index loads, register ranges, bitplanes and audio) become available.
+     * Existing memory contents are kept, so this can be called before or after loading
+     * a ROM. */
+    pub fn enable_xo_chip(&mut self) {
+        self.io.xo_chip = true;
+        self.io.memory.resize(XO_CHIP_MEMORY_SIZE, 0);
+    }
+
+    pub fn xo_chip(&self) -> bool {
+        self.io.xo_chip
+    }
+
+    // The 16 byte, 1 bit per sample audio pattern set by an XO-CHIP program
+    pub fn audio_pattern(&self) -> &[u8; 16] {
+        &self.io.audio_pattern
+    }
+
+    // Samples per second the audio pattern is played back at, derived from the pitch register
+    pub fn audio_playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((f64::from(self.io.pitch) - 64.0) / 48.0)
+    }
+
+    // Set once a SUPER-CHIP program executes EXIT; no further instructions are run
+    pub fn exited(&self) -> bool {
+        self.cpu.exited
+    }
+
+    // Registers and memory, for debuggers and other tools
+
+    pub fn register(&self, x: usize) -> u8 {
+        self.cpu.registers[x] as u8
+    }
+
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.cpu.registers[x] = value as usize;
+    }
+
+    pub fn pc(&self) -> usize {
+        self.cpu.pc
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.cpu.pc = pc;
+    }
+
+    pub fn index(&self) -> usize {
+        self.cpu.index
+    }
+
+    pub fn set_index(&mut self, index: usize) {
+        self.cpu.index = index;
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.cpu.delay_timer as u8
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.cpu.delay_timer = value as usize;
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.cpu.sound_timer as u8
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.cpu.sound_timer = value as usize;
+    }
+
+    // Return addresses of the subroutines currently being run, innermost last
+    pub fn stack(&self) -> &[usize] {
+        &self.io.stack
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.io.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.io.memory
+    }
+
+    // The instruction at the program counter, or None if it's outside memory
+    pub fn next_instruction(&self) -> Option<Instruction> {
+        Instruction::fetch(&self.io.memory, self.cpu.pc)
+    }
+
+    pub fn press_key(&mut self, key: usize) {
+        self.io.key_inputs[key] = 1;
+    }
+
+    pub fn unpress_key(&mut self, key: usize) {
+        self.io.key_inputs[key] = 0;
+    }
+
+    pub fn key_pressed(&self, key: usize) -> bool {
+        self.io.key_inputs[key] != 0
+    }
+}
+
+fn process_opcode(
+    io: &mut IOState,
+    cpu: &mut CpuState,
+    quirks: &Quirks,
+    instruction: Instruction,
+) -> Result<(), Chip8Error> {
+    // Address of the instruction being executed, for error reporting
+    let pc = cpu.pc - instruction.size();
+
+    if instruction.is_xo_chip() && !io.xo_chip {
+        let op_code = instruction.encode() as usize;
+        return Err(Chip8Error::UnknownOpcode { pc, op_code });
+    }
+
+    match instruction {
+        // SYS addr
+        Instruction::Sys(_) => {
+            // Calls to machine code are ignored
+        }
+        // CLS
+        Instruction::Cls => {
+            io.display.clear();
+        }
+        // RET
+        Instruction::Ret => match io.stack.pop() {
+            Some(addr) => {
+                cpu.pc = addr;
+            }
+            None => return Err(Chip8Error::StackUnderflow { pc }),
+        },
+        // SCD nibble
+        Instruction::Scd(nibble) => {
+            io.display.scroll((0, nibble as isize));
+        }
+        // SCR
+        Instruction::Scr => {
+            io.display.scroll((4, 0));
+        }
+        // SCL
+        Instruction::Scl => {
+            io.display.scroll((-4, 0));
+        }
+        // EXIT
+        Instruction::Exit => {
+            cpu.exited = true;
+        }
+        // LOW
+        Instruction::Low => {
+            io.display.set_hires(false);
+        }
+        // HIGH
+        Instruction::High => {
+            io.display.set_hires(true);
+        }
+        // JP addr
+        Instruction::Jp(addr) => {
+            cpu.pc = addr as usize;
+        }
+        // CALL addr
+        Instruction::Call(addr) => {
+            if io.stack.len() >= STACK_SIZE {
+                return Err(Chip8Error::StackOverflow { pc });
+            }
+
+            io.stack.push(cpu.pc);
+            cpu.pc = addr as usize;
+        }
+        // SE Vx, byte
+        Instruction::SeByte { x, byte } => {
+            if cpu.registers[x as usize] == byte as usize {
+                skip_next_instruction(io, cpu);
+            }
+        }
+        // SNE Vx, byte
+        Instruction::SneByte { x, byte } => {
+            if cpu.registers[x as usize] != byte as usize {
+                skip_next_instruction(io, cpu);
+            }
+        }
+        // SE Vx, Vy
+        Instruction::SeReg { x, y } => {
+            if cpu.registers[x as usize] == cpu.registers[y as usize] {
+                skip_next_instruction(io, cpu);
+            }
+        }
+        // SAVE Vx - Vy
+        Instruction::Save { x, y } => {
+            let (vx, vy) = (x as usize, y as usize);
+            let count = vx.max(vy) - vx.min(vy) + 1;
+            let range = memory_range(io, cpu.index, count, pc)?;
+
+            for (address, register) in range.zip(register_range(vx, vy)) {
+                io.memory[address] = cpu.registers[register] as u8;
+            }
+        }
+        // LOAD Vx - Vy
+        Instruction::Load { x, y } => {
+            let (vx, vy) = (x as usize, y as usize);
+            let count = vx.max(vy) - vx.min(vy) + 1;
+            let range = memory_range(io, cpu.index, count, pc)?;
+
+            for (address, register) in range.zip(register_range(vx, vy)) {
+                cpu.registers[register] = io.memory[address] as usize;
+            }
+        }
+        // LD Vx, byte
+        Instruction::LdByte { x, byte } => {
+            cpu.registers[x as usize] = byte as usize;
+        }
+        // ADD Vx, byte
+        Instruction::AddByte { x, byte } => {
+            let mut result = cpu.registers[x as usize] + byte as usize;
+            result &= 0xFF;
+
+            cpu.registers[x as usize] = result;
+        }
+        // LD Vx, Vy
+        Instruction::LdReg { x, y } => {
+            cpu.registers[x as usize] = cpu.registers[y as usize];
+        }
+        // OR Vx, Vy
+        Instruction::Or { x, y } => {
+            let result = cpu.registers[x as usize] | cpu.registers[y as usize];
+            cpu.registers[x as usize] = result;
+
+            if quirks.vf_reset {
+                cpu.registers[15] = 0;
+            }
+        }
+        // AND Vx, Vy
+        Instruction::And { x, y } => {
+            let result = cpu.registers[x as usize] & cpu.registers[y as usize];
+            cpu.registers[x as usize] = result;
+
+            if quirks.vf_reset {
+                cpu.registers[15] = 0;
+            }
+        }
+        // XOR Vx, Vy
+        Instruction::Xor { x, y } => {
+            let result = cpu.registers[x as usize] ^ cpu.registers[y as usize];
+            cpu.registers[x as usize] = result;
+
+            if quirks.vf_reset {
+                cpu.registers[15] = 0;
+            }
+        }
+        // ADD Vx, Vy
+        Instruction::AddReg { x, y } => {
+            let mut result = cpu.registers[x as usize] + cpu.registers[y as usize];
+            let mut carry = 0;
+
+            if result > 255 {
+                result &= 0xFF;
+                carry = 1;
+            }
+
+            cpu.registers[x as usize] = result;
+            cpu.registers[15] = carry;
+        }
+        // SUB Vx, Vy
+        Instruction::Sub { x, y } => {
+            let (vx, vy) = (cpu.registers[x as usize], cpu.registers[y as usize]);
+            cpu.registers[15] = if vx > vy { 1 } else { 0 };
+
+            let mut result = (vx as isize) - (vy as isize);
+            result &= 0xFF;
+
+            cpu.registers[x as usize] = result as usize;
+        }
+        // SHR Vx {, Vy}
+        Instruction::Shr { x, y } => {
+            let source = if quirks.shift_uses_vy { y } else { x };
+            let value = cpu.registers[source as usize];
+
+            cpu.registers[x as usize] = value / 2;
+            cpu.registers[15] = value & 0x01;
+        }
+        // SUBN Vx, Vy
+        Instruction::Subn { x, y } => {
+            let (vx, vy) = (cpu.registers[x as usize], cpu.registers[y as usize]);
+            cpu.registers[15] = if vy > vx { 1 } else { 0 };
+
+            let mut result = (vy as isize) - (vx as isize);
+            result &= 0xFF;
+
+            cpu.registers[x as usize] = result as usize;
+        }
+        // SHL Vx {, Vy}
+        Instruction::Shl { x, y } => {
+            let source = if quirks.shift_uses_vy { y } else { x };
+            let value = cpu.registers[source as usize];
+
+            let mut result = value * 2;
+            result &= 0xFF;
+
+            cpu.registers[x as usize] = result;
+            cpu.registers[15] = if value & 0x80 > 0 { 1 } else { 0 };
+        }
+        // SNE Vx, Vy
+        Instruction::SneReg { x, y } => {
+            if cpu.registers[x as usize] != cpu.registers[y as usize] {
+                skip_next_instruction(io, cpu);
+            }
+        }
+        // LD I, addr
+        Instruction::LdI(addr) => {
+            cpu.index = addr as usize;
+        }
+        // JP V0, addr
+        Instruction::JpV0(addr) => {
+            // With the jump quirk, the high nibble of the address picks the register
+            let offset = if quirks.jump_with_vx {
+                cpu.registers[(addr >> 8) as usize]
+            } else {
+                cpu.registers[0]
+            };
+
+            cpu.pc = offset + addr as usize;
+        }
+        // RND Vx, byte
+        Instruction::Rnd { x, byte } => {
+            cpu.registers[x as usize] = (cpu.rng.next_byte() & byte) as usize;
+        }
+        // DRW Vx, Vy, nibble
+        Instruction::Drw { x, y, n } => {
+            // DRW Vx, Vy, 0 draws a 16x16 sprite made of 32 bytes
+            let (sprite_width, sprite_len) = match n {
+                0 => (16, 32),
+                nibble => (8, nibble as usize),
+            };
+            let coords = (cpu.registers[x as usize], cpu.registers[y as usize]);
+
+            // With both XO-CHIP planes selected, the data for plane 2 follows plane 1
+            let mut vf = 0;
+            let mut sprite_index = cpu.index;
+            let planes = io.display.planes();
+
+            for plane in [1, 2].iter().filter(|&plane| planes & plane > 0) {
+                let sprite = &io.memory[memory_range(io, sprite_index, sprite_len, pc)?];
+
+                if io
+                    .display
+                    .draw_sprite(sprite, sprite_width, *plane, coords, quirks.clip_sprites)
+                {
+                    vf = 1;
+                }
+                sprite_index += sprite_len;
+            }
+
+            cpu.registers[15] = vf;
+        }
+        // SKP Vx
+        Instruction::Skp(x) => {
+            if io.key_inputs[cpu.registers[x as usize] & 0x0F] > 0 {
+                skip_next_instruction(io, cpu);
+            }
+        }
+        // SKNP Vx
+        Instruction::Sknp(x) => {
+            if io.key_inputs[cpu.registers[x as usize] & 0x0F] == 0 {
+                skip_next_instruction(io, cpu);
+            }
+        }
+        // LD I, long nnnn
+        Instruction::LdILong(addr) => {
+            cpu.index = addr as usize;
+        }
+        // PLANE n
+        Instruction::Plane(n) => {
+            io.display.select_planes(n);
+        }
+        // AUDIO
+        Instruction::Audio => {
+            let range = memory_range(io, cpu.index, 16, pc)?;
+            io.audio_pattern.copy_from_slice(&io.memory[range]);
+        }
+        // LD Vx, DT
+        Instruction::LdVxDt(x) => {
+            cpu.registers[x as usize] = cpu.delay_timer;
+        }
+        // LD Vx, K
+        Instruction::LdVxK(x) => {
+            match io.key_inputs.iter().position(|&x| x == 1) {
+                Some(key) => {
+                    cpu.waiting = false;
+                    cpu.registers[x as usize] = key;
+                }
+                None => {
+                    cpu.waiting = true;
+                }
+            }
+
+            if cpu.waiting {
+                cpu.pc -= 2;
+            }
+        }
+        // LD DT, Vx
+        Instruction::LdDtVx(x) => {
+            cpu.delay_timer = cpu.registers[x as usize];
+        }
+        // LD ST, Vx
+        Instruction::LdStVx(x) => {
+            cpu.sound_timer = cpu.registers[x as usize];
+        }
+        // ADD I, Vx
+        Instruction::AddI(x) => {
+            // I is 16 bits wide, even if memory is smaller
+            cpu.index = cpu.index.wrapping_add(cpu.registers[x as usize]) & 0xFFFF;
+        }
+        // LD F, Vx
+        Instruction::LdF(x) => {
+            cpu.index = (5 * cpu.registers[x as usize]) & 0xFFF;
+        }
+        // LD HF, Vx
+        Instruction::LdHf(x) => {
+            cpu.index = BIG_FONT_ADDRESS + 10 * (cpu.registers[x as usize] & 0x0F);
+        }
+        // LD B, Vx
+        Instruction::LdB(x) => {
+            let value = cpu.registers[x as usize];
+            let ones = value % 10;
+            let tens = (value / 10) % 10;
+            let hundreds = (value / 100) % 10;
+
+            let range = memory_range(io, cpu.index, 3, pc)?;
+
+            io.memory[range.start] = hundreds as u8;
+            io.memory[range.start + 1] = tens as u8;
+            io.memory[range.start + 2] = ones as u8;
+        }
+        // PITCH Vx
+        Instruction::Pitch(x) => {
+            io.pitch = cpu.registers[x as usize] as u8;
+        }
+        // LD [I], Vx
+        Instruction::LdIVx(x) => {
+            let vx = x as usize;
+            let register_slice = &cpu.registers[0..(vx + 1)];
+            let range = memory_range(io, cpu.index, vx + 1, pc)?;
+
+            for (address, byte) in range.zip(register_slice.iter()) {
+                io.memory[address] = *byte as u8;
+            }
+
+            if quirks.load_store_increments_i {
+                cpu.index += vx + 1;
+            } else if quirks.load_store_increments_i_by_x {
+                cpu.index += vx;
+            }
+        }
+        // LD Vx, [I]
+        Instruction::LdVxI(x) => {
+            let vx = x as usize;
+            let memory_slice = &io.memory[memory_range(io, cpu.index, vx + 1, pc)?];
+
+            for (i, byte) in memory_slice.iter().enumerate() {
+                cpu.registers[i] = *byte as usize;
+            }
+
+            if quirks.load_store_increments_i {
+                cpu.index += vx + 1;
+            } else if quirks.load_store_increments_i_by_x {
+                cpu.index += vx;
+            }
+        }
+        // LD R, Vx
+        Instruction::LdRVx(x) => {
+            for i in 0..=(x as usize) {
+                io.rpl_flags[i] = cpu.registers[i] as u8;
+            }
+        }
+        // LD Vx, R
+        Instruction::LdVxR(x) => {
+            for i in 0..=(x as usize) {
+                cpu.registers[i] = io.rpl_flags[i] as usize;
+            }
+        }
+        Instruction::Unknown(op_code) => {
+            let op_code = op_code as usize;
+            return Err(Chip8Error::UnknownOpcode { pc, op_code });
+        }
+    }
+
+    Ok(())
+}
+
+/* Skip the instruction at the program counter. In XO-CHIP mode this steps over both
+ * words of an F000 nnnn long index load. */
+fn skip_next_instruction(io: &IOState, cpu: &mut CpuState) {
+    let long_load = io.xo_chip
+        && io.memory.get(cpu.pc) == Some(&0xF0)
+        && io.memory.get(cpu.pc + 1) == Some(&0x00);
+
+    cpu.pc += if long_load { 4 } else { 2 };
+}
+
+/* Check that len bytes starting at address fit in memory, and return their range.
+ * pc is the address of the instruction making the access. */
+fn memory_range(
+    io: &IOState,
+    address: usize,
+    len: usize,
+    pc: usize,
+) -> Result<Range<usize>, Chip8Error> {
+    match address.checked_add(len) {
+        Some(end) if end <= io.memory.len() => Ok(address..end),
+        _ => Err(Chip8Error::MemoryOutOfRange {
+            pc,
+            address: address.max(io.memory.len()),
+        }),
+    }
+}
+
+// Registers from Vx to Vy, counting down if y < x
+fn register_range(vx: usize, vy: usize) -> Box<dyn Iterator<Item = usize>> {
+    if vx <= vy {
+        Box::new(vx..=vy)
+    } else {
+        Box::new((vy..=vx).rev())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run the first steps of a ROM, with XO-CHIP enabled if asked
+    fn run(rom: &[u8], steps: usize, xo_chip: bool) -> Chip8 {
+        let mut system = Chip8::with_seed(600, Quirks::default(), 1);
+        if xo_chip {
+            system.enable_xo_chip();
+        }
+        system.load_rom_bytes(rom).unwrap();
+
+        for _ in 0..steps {
+            system.step().unwrap();
+        }
+        system
+    }
+
+    #[test]
+    fn skips_step_over_long_index_loads() {
+        // LD V0, 5; SE V0, 5; LD I, long 0x1234; LD V1, 1
+        let rom = [0x60, 0x05, 0x30, 0x05, 0xF0, 0x00, 0x12, 0x34, 0x61, 0x01];
+
+        let system = run(&rom, 2, true);
+        assert_eq!(system.pc(), 0x208);
+        let system = run(&rom, 3, true);
+        assert_eq!((system.index(), system.register(1)), (0, 1));
+
+        // Without XO-CHIP, F000 is just another instruction to skip
+        let system = run(&rom, 2, false);
+        assert_eq!(system.pc(), 0x206);
+    }
+
+    #[test]
+    fn save_and_load_ranges_can_run_backwards() {
+        let rom = [
+            0x61, 0x01, // LD V1, 1
+            0x62, 0x02, // LD V2, 2
+            0x63, 0x03, // LD V3, 3
+            0xA3, 0x00, // LD I, 0x300
+            0x53, 0x12, // SAVE V3 - V1
+            0xA3, 0x10, // LD I, 0x310
+            0x53, 0x13, // LOAD V3 - V1
+        ];
+
+        let mut system = run(&rom, 5, true);
+        assert_eq!(&system.memory()[0x300..0x303], &[3, 2, 1]);
+        assert_eq!(system.index(), 0x300);
+
+        system.memory_mut()[0x310..0x313].copy_from_slice(&[7, 8, 9]);
+        system.run_cycles(2).unwrap();
+        let registers: Vec<u8> = (1..4).map(|x| system.register(x)).collect();
+        assert_eq!(registers, vec![9, 8, 7]);
+    }
+
+    #[test]
+    fn clearing_and_scrolling_only_touch_selected_planes() {
+        let rom = [
+            0xF3, 0x01, // PLANE 3
+            0xA2, 0x0E, // LD I, 0x20E
+            0xD0, 0x01, // DRW V0, V0, 1
+            0xF1, 0x01, // PLANE 1
+            0x00, 0xE0, // CLS
+            0xF2, 0x01, // PLANE 2
+            0x00, 0xFB, // SCR
+            0x80, 0x80, // One pixel for each plane
+        ];
+
+        let mut system = run(&rom, 3, true);
+        assert_eq!(system.pixel(0, 0), 3);
+        system.run_cycles(2).unwrap();
+        assert_eq!(system.pixel(0, 0), 2);
+        system.run_cycles(2).unwrap();
+        assert_eq!((system.pixel(0, 0), system.pixel(4, 0)), (0, 2));
+    }
+
+    // Run a ROM until it fails, returning the error
+    fn error(rom: &[u8]) -> Chip8Error {
+        let mut system = run(rom, 0, false);
+        let error = system.run_cycles(100).unwrap_err();
+
+        // The machine stays halted on the failing instruction
+        let pc = system.pc();
+        assert_eq!(system.step(), Err(error.clone()));
+        assert_eq!(system.halted(), Some(&error));
+        assert_eq!(system.pc(), pc);
+        error
+    }
+
+    #[test]
+    fn bad_programs_halt() {
+        // CALL 0x200, forever
+        let pc = 0x200;
+        assert_eq!(error(&[0x22, 0x00]), Chip8Error::StackOverflow { pc });
+        // LD V0, 0; RET
+        let pc = 0x202;
+        assert_eq!(
+            error(&[0x60, 0x00, 0x00, 0xEE]),
+            Chip8Error::StackUnderflow { pc }
+        );
+        let op_code = 0x5001;
+        assert_eq!(
+            error(&[0x50, 0x01]),
+            Chip8Error::UnknownOpcode { pc: 0x200, op_code }
+        );
+        // XO-CHIP instructions are unknown until XO-CHIP is enabled
+        let op_code = 0xF001;
+        assert_eq!(
+            error(&[0xF0, 0x01]),
+            Chip8Error::UnknownOpcode { pc: 0x200, op_code }
+        );
+        // JP 0xFFF, which only leaves room for half an instruction
+        let pc = 0xFFF;
+        assert_eq!(error(&[0x1F, 0xFF]), Chip8Error::PcOutOfRange { pc });
+        // LD I, 0xFFE; LD [I], V3, which fails at the first address past the end
+        let (pc, address) = (0x202, 0x1000);
+        let rom = [0xAF, 0xFE, 0xF3, 0x55];
+        assert_eq!(error(&rom), Chip8Error::MemoryOutOfRange { pc, address });
+    }
+
+    #[test]
+    fn huge_indexes_are_out_of_range() {
+        // LD [I], V0
+        let mut system = run(&[0xF0, 0x55], 0, true);
+        system.set_index(usize::MAX);
+
+        let (pc, address) = (0x200, usize::MAX);
+        assert_eq!(
+            system.step(),
+            Err(Chip8Error::MemoryOutOfRange { pc, address })
+        );
+    }
+
+    #[test]
+    fn adding_to_i_wraps_at_16_bits() {
+        // LD V0, 2; ADD I, V0
+        let mut system = run(&[0x60, 0x02, 0xF0, 0x1E], 1, true);
+        system.set_index(0xFFFF);
+        system.step().unwrap();
+        assert_eq!(system.index(), 1);
+    }
+
+    #[test]
+    fn roms_must_fit_in_memory() {
+        let mut system = Chip8::with_seed(600, Quirks::default(), 1);
+        assert!(matches!(system.load_rom_bytes(&[]), Err(RomError::Empty)));
+        assert!(matches!(
+            system.load_rom_reader(&[][..]),
+            Err(RomError::Empty)
+        ));
+
+        // 4 KiB of memory, less the 512 bytes before the load address
+        assert_eq!(system.load_rom_bytes(&[0xAA; 0xE00]).unwrap(), 0xE00);
+        assert_eq!(system.memory()[0xFFF], 0xAA);
+        let result = system.load_rom_bytes(&[0; 0xE01]);
+        assert!(matches!(
+            result,
+            Err(RomError::TooLarge {
+                size: 0xE01,
+                max: 0xE00
+            })
+        ));
+
+        system.set_load_address(0x600);
+        let result = system.load_rom_reader(&[0; 0xA01][..]);
+        assert!(matches!(
+            result,
+            Err(RomError::TooLarge {
+                size: 0xA01,
+                max: 0xA00
+            })
+        ));
+
+        // XO-CHIP programs get 64 KiB
+        let mut system = Chip8::with_seed(600, Quirks::default(), 1);
+        system.enable_xo_chip();
+        assert_eq!(system.load_rom_bytes(&[0xAA; 0xE01]).unwrap(), 0xE01);
+        assert_eq!(system.load_rom_bytes(&[0xBB; 0xFE00]).unwrap(), 0xFE00);
+        assert_eq!(system.memory()[0xFFFF], 0xBB);
+        let result = system.load_rom_bytes(&[0; 0xFE01]);
+        assert!(matches!(
+            result,
+            Err(RomError::TooLarge {
+                size: 0xFE01,
+                max: 0xFE00
+            })
+        ));
+    }
+
+    #[test]
+    fn timers_count_down_at_60hz_at_any_clock_speed() {
+        // LD V0, 60; LD DT, V0; JP 0x204
+        let rom = [0x60, 0x3C, 0xF0, 0x15, 0x12, 0x04];
+
+        for &clock_speed in [1, 7, 59, 60, 600, 1000].iter() {
+            let mut system = Chip8::with_seed(clock_speed, Quirks::default(), 1);
+            system.load_rom_bytes(&rom).unwrap();
+            system.run_for(Duration::from_secs(1)).unwrap();
+            assert_eq!(system.frame_count(), 60);
+            assert_eq!(system.cycle_count(), clock_speed as u64);
+        }
+
+        // At 7 Hz, LD DT runs a 7th of a second in, after 8 of the 60 countdowns
+        let mut system = Chip8::with_seed(7, Quirks::default(), 1);
+        system.load_rom_bytes(&rom).unwrap();
+        system.run_for(Duration::from_secs(1)).unwrap();
+        assert_eq!(system.delay_timer(), 8);
+
+        // Whole frames at a time, cycles are spread out between them
+        let mut system = Chip8::with_seed(7, Quirks::default(), 1);
+        system.load_rom_bytes(&rom).unwrap();
+        for _ in 0..60 {
+            system.run_frame().unwrap();
+        }
+        assert_eq!((system.frame_count(), system.cycle_count()), (60, 7));
+        assert_eq!(system.delay_timer(), 8);
+    }
+
+    #[test]
+    fn leftover_time_carries_over() {
+        // JP 0x200
+        let mut system = Chip8::with_seed(1000, Quirks::default(), 1);
+        system.load_rom_bytes(&[0x12, 0x00]).unwrap();
+
+        // Less than a cycle each time
+        for _ in 0..1000 {
+            system.run_for(Duration::from_micros(999)).unwrap();
+        }
+        assert_eq!((system.cycle_count(), system.frame_count()), (999, 59));
+
+        system.run_for(Duration::from_millis(1)).unwrap();
+        assert_eq!((system.cycle_count(), system.frame_count()), (1000, 60));
+    }
+
+    #[test]
+    #[should_panic]
+    fn clock_speed_must_not_be_zero() {
+        Chip8::with_seed(0, Quirks::default(), 1);
+    }
+}
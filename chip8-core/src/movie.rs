@@ -0,0 +1,421 @@
+use crate::{Chip8, MovieError, Quirks};
+use std::fmt;
+
+/* A recording of a play session that can be played back exactly.
+ *
+ * A movie holds everything needed to set the machine up the same way again (a hash of
+ * the ROM, the random seed and the machine settings) plus every key press and release
+ * with the frame it happened on. Key events only take effect between frames, so a
+ * movie played back a frame at a time with run_frame repeats the original run exactly.
+ *
+ * Movies are saved as text, one setting or event per line:
+ *
+ *     chip8-movie 2
+ *     rom 9f1c2e5d0a7b3c41
+ *     seed 1234
+ *     clock 600
+ *     quirks vf_reset clip_sprites
+ *     xo-chip 0
+ *     load-address 0x200
+ *     12 press 5
+ *     20 release 5
+ *     end 400 */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub seed: u64,
+    pub clock_speed: usize,
+    pub quirks: Quirks,
+    pub xo_chip: bool,
+    pub load_address: usize,
+    // The frame the recording stopped on
+    pub end_frame: u64,
+    events: Vec<KeyEvent>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub key: usize,
+    pub pressed: bool,
+}
+
+const HEADER: &str = "chip8-movie 2";
+
+/* Version 1 movies were written before the load_store_increments_i_by_x quirk existed.
+ * Everything in them means the same in version 2, so they're still read. */
+const OLD_HEADERS: [&str; 1] = ["chip8-movie 1"];
+
+// Names used for each quirk in the quirks line
+const QUIRK_NAMES: [&str; 7] = [
+    "shift_uses_vy",
+    "load_store_increments_i",
+    "load_store_increments_i_by_x",
+    "jump_with_vx",
+    "vf_reset",
+    "clip_sprites",
+    "display_wait",
+];
+
+impl Movie {
+    /* Start recording a machine that has just been set up and had rom loaded, before it
+     * has run. */
+    pub fn new(system: &Chip8, rom: &[u8]) -> Movie {
+        Movie {
+            rom_hash: rom_hash(rom),
+            seed: system.seed(),
+            clock_speed: system.clock_speed(),
+            quirks: system.quirks(),
+            xo_chip: system.xo_chip(),
+            load_address: system.load_address(),
+            end_frame: 0,
+            events: Vec::new(),
+        }
+    }
+
+    /* Set up a machine the same way as the recorded one and load the ROM into it. Fails
+     * if rom isn't the ROM the movie was recorded with. */
+    pub fn machine(&self, rom: &[u8]) -> Result<Chip8, MovieError> {
+        let found = rom_hash(rom);
+        if found != self.rom_hash {
+            return Err(MovieError::RomMismatch {
+                expected: self.rom_hash,
+                found,
+            });
+        }
+
+        let mut system = Chip8::with_seed(self.clock_speed, self.quirks, self.seed);
+        if self.xo_chip {
+            system.enable_xo_chip();
+        }
+        system.set_load_address(self.load_address);
+        system.load_rom_bytes(rom).map_err(MovieError::Rom)?;
+
+        Ok(system)
+    }
+
+    // Add a key event. Events must be recorded in order.
+    pub fn record(&mut self, frame: u64, key: usize, pressed: bool) {
+        self.events.push(KeyEvent {
+            frame,
+            key,
+            pressed,
+        });
+        self.end_frame = self.end_frame.max(frame);
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, MovieError> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+        match lines.next() {
+            Some((_, header)) if header == HEADER || OLD_HEADERS.contains(&header) => {}
+            Some((line, _)) => return Err(parse_error(line, "not a chip8 movie")),
+            None => return Err(parse_error(0, "movie is empty")),
+        }
+
+        let mut movie = Movie {
+            rom_hash: 0,
+            seed: 0,
+            clock_speed: 0,
+            quirks: Quirks::default(),
+            xo_chip: false,
+            load_address: crate::DEFAULT_LOAD_ADDRESS,
+            end_frame: 0,
+            events: Vec::new(),
+        };
+        let mut has_rom = false;
+
+        for (line, text) in lines {
+            let words: Vec<&str> = text.split_whitespace().collect();
+            let invalid = || parse_error(line, &format!("invalid line '{}'", text));
+
+            match words.as_slice() {
+                ["rom", hash] => {
+                    movie.rom_hash = u64::from_str_radix(hash, 16).map_err(|_| invalid())?;
+                    has_rom = true;
+                }
+                ["seed", seed] => movie.seed = seed.parse().map_err(|_| invalid())?,
+                ["clock", clock] => movie.clock_speed = clock.parse().map_err(|_| invalid())?,
+                ["quirks", names @ ..] => {
+                    movie.quirks = parse_quirks(names).ok_or_else(invalid)?;
+                }
+                // Earlier movies named the random number generator, which is always xorshift
+                ["rng", "xorshift"] => {}
+                ["xo-chip", "0"] => movie.xo_chip = false,
+                ["xo-chip", "1"] => movie.xo_chip = true,
+                ["load-address", address] => {
+                    let hex = address.strip_prefix("0x").ok_or_else(invalid)?;
+                    movie.load_address = usize::from_str_radix(hex, 16).map_err(|_| invalid())?;
+                }
+                ["end", frame] => {
+                    movie.end_frame = frame.parse().map_err(|_| invalid())?;
+                }
+                [frame, action @ ("press" | "release"), key] => {
+                    let frame: u64 = frame.parse().map_err(|_| invalid())?;
+                    let key = usize::from_str_radix(key, 16)
+                        .ok()
+                        .filter(|&key| key < 16)
+                        .ok_or_else(invalid)?;
+
+                    if movie.events.last().is_some_and(|last| last.frame > frame) {
+                        return Err(parse_error(line, "events are out of order"));
+                    }
+
+                    movie.record(frame, key, *action == "press");
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        if !has_rom || movie.clock_speed == 0 {
+            return Err(parse_error(0, "missing rom or clock line"));
+        }
+
+        Ok(movie)
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "rom {:016x}", self.rom_hash)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "clock {}", self.clock_speed)?;
+
+        let quirks: Vec<&str> = QUIRK_NAMES
+            .iter()
+            .zip(quirk_values(&self.quirks).iter())
+            .filter(|(_, &enabled)| enabled)
+            .map(|(name, _)| *name)
+            .collect();
+        writeln!(f, "quirks {}", quirks.join(" "))?;
+
+        writeln!(f, "xo-chip {}", self.xo_chip as u8)?;
+        writeln!(f, "load-address {:#05x}", self.load_address)?;
+
+        for event in self.events.iter() {
+            let action = if event.pressed { "press" } else { "release" };
+            writeln!(f, "{} {} {:X}", event.frame, action, event.key)?;
+        }
+
+        writeln!(f, "end {}", self.end_frame)
+    }
+}
+
+/* Feeds a movie's key events to a machine. Call apply before every frame; playback is
+ * finished once the machine reaches the movie's end frame. */
+pub struct Playback {
+    movie: Movie,
+    next: usize,
+}
+
+impl Playback {
+    pub fn new(movie: Movie) -> Playback {
+        Playback { movie, next: 0 }
+    }
+
+    // Press and release keys for every event due on the machine's current frame
+    pub fn apply(&mut self, system: &mut Chip8) {
+        let frame = system.frame_count();
+
+        while let Some(event) = self.movie.events.get(self.next) {
+            if event.frame > frame {
+                break;
+            }
+
+            if event.pressed {
+                system.press_key(event.key);
+            } else {
+                system.unpress_key(event.key);
+            }
+            self.next += 1;
+        }
+    }
+
+    pub fn finished(&self, system: &Chip8) -> bool {
+        self.next >= self.movie.events.len() && system.frame_count() >= self.movie.end_frame
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+}
+
+// 64-bit FNV-1a hash, used to check a movie is played back with the right ROM
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+fn quirk_values(quirks: &Quirks) -> [bool; 7] {
+    [
+        quirks.shift_uses_vy,
+        quirks.load_store_increments_i,
+        quirks.load_store_increments_i_by_x,
+        quirks.jump_with_vx,
+        quirks.vf_reset,
+        quirks.clip_sprites,
+        quirks.display_wait,
+    ]
+}
+
+fn parse_quirks(names: &[&str]) -> Option<Quirks> {
+    let mut values = [false; 7];
+
+    for name in names.iter() {
+        let i = QUIRK_NAMES.iter().position(|quirk| quirk == name)?;
+        values[i] = true;
+    }
+
+    Some(Quirks {
+        shift_uses_vy: values[0],
+        load_store_increments_i: values[1],
+        load_store_increments_i_by_x: values[2],
+        jump_with_vx: values[3],
+        vf_reset: values[4],
+        clip_sprites: values[5],
+        display_wait: values[6],
+    })
+}
+
+fn parse_error(line: usize, message: &str) -> MovieError {
+    MovieError::Parse {
+        line,
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // Adds up which keys are held each frame, so the result depends on every key event
+    const PROGRAM: &str = "
+        loop:
+            LD V1, 0
+        keys:
+            SKNP V1
+            ADD V0, V1
+            ADD V1, 1
+            SE V1, 16
+            JP keys
+            RND V2, 0xFF
+            ADD V0, V2
+            LD V3, 1
+            LD DT, V3
+        wait:
+            LD V3, DT
+            SE V3, 0
+            JP wait
+            JP loop
+    ";
+
+    fn rom() -> Vec<u8> {
+        assemble(PROGRAM, "test.asm").unwrap()
+    }
+
+    fn recorded() -> (Movie, Chip8) {
+        let rom = rom();
+        let mut system = Chip8::with_seed(600, Quirks::cosmac_vip(), 99);
+        system.enable_xo_chip();
+        system.load_rom_bytes(&rom).unwrap();
+
+        let mut movie = Movie::new(&system, &rom);
+        let events = [
+            (3, 5, true),
+            (3, 0xA, true),
+            (10, 5, false),
+            (25, 0xA, false),
+        ];
+        for frame in 0..40 {
+            for &(_, key, pressed) in events.iter().filter(|event| event.0 == frame) {
+                movie.record(frame, key, pressed);
+                if pressed {
+                    system.press_key(key);
+                } else {
+                    system.unpress_key(key);
+                }
+            }
+            system.run_frame().unwrap();
+        }
+        movie.end_frame = system.frame_count();
+
+        (movie, system)
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let (movie, _) = recorded();
+        let text = movie.to_string();
+
+        assert_eq!(Movie::parse(&text).unwrap(), movie);
+        assert!(!text.contains("rng"));
+        assert!(text.contains("quirks shift_uses_vy load_store_increments_i vf_reset"));
+        assert!(text.contains("3 press A"));
+    }
+
+    #[test]
+    fn playback_repeats_the_recording() {
+        let (movie, recorded) = recorded();
+        let movie = Movie::parse(&movie.to_string()).unwrap();
+        let mut system = movie.machine(&rom()).unwrap();
+        let mut playback = Playback::new(movie);
+
+        while !playback.finished(&system) {
+            playback.apply(&mut system);
+            system.run_frame().unwrap();
+        }
+
+        assert_eq!(system.save_state(), recorded.save_state());
+    }
+
+    #[test]
+    fn wrong_rom_is_rejected() {
+        let (movie, _) = recorded();
+
+        assert!(matches!(
+            movie.machine(&[0x12, 0x00]),
+            Err(MovieError::RomMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn bad_movies_are_rejected() {
+        let header = "chip8-movie 1\nrom 1f\nclock 600\n";
+        let line = |text: &str| match Movie::parse(text) {
+            Err(MovieError::Parse { line, .. }) => line,
+            result => panic!("expected a parse error, got {:?}", result),
+        };
+
+        assert_eq!(line(""), 0);
+        assert_eq!(line("chip8-movie 3"), 1);
+        assert_eq!(line("chip8-movie 1\nclock 600"), 0);
+        assert_eq!(line(&format!("{}5 press G", header)), 4);
+        assert_eq!(line(&format!("{}quirks fast", header)), 4);
+        assert_eq!(line(&format!("{}rng counter", header)), 4);
+        assert_eq!(line(&format!("{}xo-chip yes", header)), 4);
+        assert_eq!(
+            line(&format!("{}9 press 1\n\n# note\n8 release 1", header)),
+            7
+        );
+
+        assert!(Movie::parse(&format!("{}# a comment\n\nend 5", header)).is_ok());
+        assert!(Movie::parse(&format!("{}rng xorshift", header)).is_ok());
+        assert!(
+            Movie::parse(&format!("{}xo-chip 1", header))
+                .unwrap()
+                .xo_chip
+        );
+        assert!(Movie::parse(&header.replace("movie 1", "movie 2")).is_ok());
+    }
+}
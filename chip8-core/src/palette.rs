@@ -0,0 +1,144 @@
+/* The colours the screen is drawn in. There's one for every combination of bitplanes a
+ * pixel can have switched on: none (the background), plane 1 (the foreground, the only
+ * one CHIP-8 and SUPER-CHIP programs can draw with), plane 2 and both planes. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub colours: [[u8; 4]; 4],
+}
+
+// Names of the built-in themes, in the order they're cycled through
+pub const THEMES: [&str; 5] = ["default", "amber", "green", "lcd", "high-contrast"];
+
+impl Default for Palette {
+    // White on black, with greys for the XO-CHIP planes
+    fn default() -> Palette {
+        Palette::from_rgb([
+            [0x00, 0x00, 0x00],
+            [0xFF, 0xFF, 0xFF],
+            [0xAA, 0xAA, 0xAA],
+            [0x55, 0x55, 0x55],
+        ])
+    }
+}
+
+impl Palette {
+    // Look up one of the built-in THEMES by name
+    pub fn from_name(name: &str) -> Option<Palette> {
+        let colours = match name {
+            "default" => return Some(Palette::default()),
+            // An amber monochrome monitor
+            "amber" => [
+                [0x1A, 0x0F, 0x00],
+                [0xFF, 0xB0, 0x00],
+                [0x99, 0x66, 0x00],
+                [0xFF, 0xDC, 0x78],
+            ],
+            // A green phosphor monitor
+            "green" => [
+                [0x00, 0x14, 0x05],
+                [0x33, 0xFF, 0x66],
+                [0x14, 0x8C, 0x32],
+                [0xAA, 0xFF, 0xBE],
+            ],
+            // Dark pixels on an olive LCD, like early handhelds
+            "lcd" => [
+                [0x9B, 0xBC, 0x0F],
+                [0x0F, 0x38, 0x0F],
+                [0x30, 0x62, 0x30],
+                [0x05, 0x1A, 0x05],
+            ],
+            // Colours that are as far apart as possible
+            "high-contrast" => [
+                [0x00, 0x00, 0x00],
+                [0xFF, 0xFF, 0xFF],
+                [0xFF, 0xFF, 0x00],
+                [0x00, 0xFF, 0xFF],
+            ],
+            _ => return None,
+        };
+
+        Some(Palette::from_rgb(colours))
+    }
+
+    fn from_rgb(colours: [[u8; 3]; 4]) -> Palette {
+        let mut palette = Palette {
+            colours: [[0; 4]; 4],
+        };
+        for (colour, rgb) in palette.colours.iter_mut().zip(colours.iter()) {
+            *colour = [rgb[0], rgb[1], rgb[2], 0xFF];
+        }
+
+        palette
+    }
+
+    pub fn background(&self) -> [u8; 4] {
+        self.colours[0]
+    }
+
+    pub fn set_background(&mut self, colour: [u8; 4]) {
+        self.colours[0] = colour;
+    }
+
+    pub fn foreground(&self) -> [u8; 4] {
+        self.colours[1]
+    }
+
+    pub fn set_foreground(&mut self, colour: [u8; 4]) {
+        self.colours[1] = colour;
+    }
+}
+
+// Parses colours written as RRGGBB hex, optionally starting with #
+pub fn parse_colour(text: &str) -> Option<[u8; 4]> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    // from_str_radix would also take a sign
+    if hex.len() != 6 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let rgb = u32::from_str_radix(hex, 16).ok()?;
+    Some([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 0xFF])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colours_are_hex_with_an_optional_hash() {
+        assert_eq!(parse_colour("FFB000"), Some([0xFF, 0xB0, 0x00, 0xFF]));
+        assert_eq!(parse_colour("#0f380f"), Some([0x0F, 0x38, 0x0F, 0xFF]));
+
+        for text in [
+            "", "#", "FFF", "#FFFFFFF", "##FFFFFF", "GGGGGG", "+FFFFF", "FF FF FF",
+        ] {
+            assert_eq!(parse_colour(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn every_theme_can_be_found_by_name() {
+        assert_eq!(Palette::from_name("default"), Some(Palette::default()));
+        assert_eq!(Palette::from_name("Amber"), None);
+        assert_eq!(Palette::from_name("nope"), None);
+
+        for name in THEMES.iter() {
+            let palette = Palette::from_name(name).unwrap();
+            assert!(palette.colours.iter().all(|colour| colour[3] == 0xFF));
+
+            // Every plane has to stand out from the background and from the others
+            for (i, a) in palette.colours.iter().enumerate() {
+                for b in palette.colours[..i].iter() {
+                    let distance: u32 = (0..3).map(|c| u32::from(a[c].abs_diff(b[c]))).sum();
+                    assert!(distance >= 0x30, "{}: {:?} and {:?}", name, a, b);
+                }
+            }
+            for colour in palette.colours[1..].iter() {
+                let distance: u32 = (0..3)
+                    .map(|c| u32::from(colour[c].abs_diff(palette.background()[c])))
+                    .sum();
+                assert!(distance >= 0x60, "{}: {:?}", name, colour);
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+/* A minimal PNG encoder for screenshots. Images are 8-bit RGBA, and the image data is
+ * wrapped in zlib using uncompressed deflate blocks: screenshots are small enough that
+ * compressing them isn't worth a dependency. */
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+// The most data an uncompressed deflate block can hold
+const MAX_BLOCK: usize = 0xFFFF;
+
+// Encode width x height RGBA pixels, given row by row, as a PNG file
+pub fn encode(width: usize, height: usize, rgba: &[u8]) -> Vec<u8> {
+    let mut png = SIGNATURE.to_vec();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, RGBA, then the default compression, filter and interlace methods
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // Every row starts with its filter type, which is always none here
+    let mut data = Vec::with_capacity(height * (width * 4 + 1));
+    for row in rgba.chunks(width * 4).take(height) {
+        data.push(0);
+        data.extend_from_slice(row);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&data));
+
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+
+    // The checksum covers the chunk type and data
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Wrap data in a zlib stream without compressing it
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+
+    // Even empty data needs one block to mark the end
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+
+        zlib.push(last as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+
+    zlib.extend_from_slice(&adler32(data).to_be_bytes());
+    zlib
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in data.iter() {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for &byte in data.iter() {
+        a = (a + u32::from(byte)) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    // Split a PNG into its chunks, checking every checksum
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&png[..8], &SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut at = 8;
+
+        while at < png.len() {
+            let len = u32::from_be_bytes(png[at..(at + 4)].try_into().unwrap()) as usize;
+            let body = &png[(at + 4)..(at + 8 + len)];
+            let crc = u32::from_be_bytes(png[(at + 8 + len)..(at + 12 + len)].try_into().unwrap());
+            assert_eq!(crc32(body), crc);
+
+            chunks.push((body[..4].try_into().unwrap(), body[4..].to_vec()));
+            at += 12 + len;
+        }
+
+        chunks
+    }
+
+    // Undo zlib_stored, checking the block headers and checksum
+    fn unzlib(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(&zlib[..2], &[0x78, 0x01]);
+        assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+        let mut data = Vec::new();
+        let mut at = 2;
+
+        loop {
+            let last = zlib[at] == 1;
+            let len = u16::from_le_bytes([zlib[at + 1], zlib[at + 2]]);
+            let check = u16::from_le_bytes([zlib[at + 3], zlib[at + 4]]);
+            assert_eq!(len, !check);
+
+            data.extend_from_slice(&zlib[(at + 5)..(at + 5 + len as usize)]);
+            at += 5 + len as usize;
+            if last {
+                break;
+            }
+        }
+
+        assert_eq!(&zlib[at..], &adler32(&data).to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn checksums_match_known_values() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(&[]), 1);
+    }
+
+    #[test]
+    fn encodes_rgba_rows() {
+        let rgba: Vec<u8> = (0..(3 * 2 * 4)).map(|i| i as u8).collect();
+        let chunks = chunks(&encode(3, 2, &rgba));
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, vec![&b"IHDR"[..], b"IDAT", b"IEND"]);
+
+        let header = &chunks[0].1;
+        assert_eq!(&header[..8], &[0, 0, 0, 3, 0, 0, 0, 2]);
+        assert_eq!(&header[8..], &[8, 6, 0, 0, 0]);
+
+        let mut expected = vec![0];
+        expected.extend_from_slice(&rgba[..12]);
+        expected.push(0);
+        expected.extend_from_slice(&rgba[12..]);
+        assert_eq!(unzlib(&chunks[1].1), expected);
+        assert!(chunks[2].1.is_empty());
+    }
+
+    #[test]
+    fn large_images_span_several_blocks() {
+        let (width, height) = (512, 256);
+        let rgba: Vec<u8> = (0..(width * height * 4)).map(|i| (i * 7) as u8).collect();
+        let chunks = chunks(&encode(width, height, &rgba));
+        let data = unzlib(&chunks[1].1);
+
+        assert!(data.len() > 4 * MAX_BLOCK);
+        for (y, row) in data.chunks(width * 4 + 1).enumerate() {
+            assert_eq!(row[0], 0);
+            assert_eq!(&row[1..], &rgba[(y * width * 4)..((y + 1) * width * 4)]);
+        }
+    }
+
+    #[test]
+    fn empty_data_still_has_a_final_block() {
+        assert!(unzlib(&zlib_stored(&[])).is_empty());
+    }
+}
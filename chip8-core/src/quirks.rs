@@ -0,0 +1,237 @@
+/* Several CHIP-8 instructions behave differently depending on which interpreter a ROM
+ * was written for. Quirks selects the interpretation used by process_opcode.
+ *
+ * Quirks::default() keeps the behaviour this emulator has always had. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift Vy into Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    // FX55/FX65 leave I pointing past the last register stored/loaded
+    pub load_store_increments_i: bool,
+    /* FX55/FX65 leave I pointing at the last register stored/loaded instead, one short
+     * of load_store_increments_i, which takes precedence if both are set */
+    pub load_store_increments_i_by_x: bool,
+    // BNNN is treated as BXNN and jumps to XNN + Vx instead of NNN + V0
+    pub jump_with_vx: bool,
+    // 8XY1/8XY2/8XY3 set VF to 0
+    pub vf_reset: bool,
+    // Sprites are cut off at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+    // DXYN waits for the next 60 Hz frame before execution continues
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    // The original interpreter on the RCA COSMAC VIP
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            load_store_increments_i_by_x: false,
+            jump_with_vx: false,
+            vf_reset: true,
+            clip_sprites: true,
+            display_wait: true,
+        }
+    }
+
+    /* CHIP-48 on the HP-48 calculators, where the shift and jump quirks come from. Its
+     * FX55/FX65 got the increment of I wrong by one, which SUPER-CHIP 1.1 later dropped
+     * altogether. */
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            load_store_increments_i_by_x: true,
+            jump_with_vx: true,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+        }
+    }
+
+    // SUPER-CHIP 1.1, which most modern CHIP-8 games are written against
+    pub fn super_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            load_store_increments_i_by_x: false,
+            jump_with_vx: true,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+        }
+    }
+
+    // XO-CHIP as implemented by Octo
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            load_store_increments_i_by_x: false,
+            jump_with_vx: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+        }
+    }
+
+    /* Look up a preset by the name used on the command line:
+     * "vip", "chip48", "schip" or "xo" */
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" => Some(Quirks::super_chip()),
+            "xo" => Some(Quirks::xo_chip()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Chip8;
+
+    // Run the first steps instructions of rom with every quirk off except those set
+    fn run(quirks: Quirks, rom: &[u8], steps: usize) -> Chip8 {
+        let mut system = Chip8::with_seed(600, quirks, 0);
+        system.load_rom_bytes(rom).unwrap();
+        for _ in 0..steps {
+            system.step().unwrap();
+        }
+
+        system
+    }
+
+    fn none() -> Quirks {
+        Quirks::default()
+    }
+
+    #[test]
+    fn shift_uses_vy() {
+        // LD V1, 0x81; LD V2, 0x02; SHR V1, V2
+        let rom = [0x61, 0x81, 0x62, 0x02, 0x81, 0x26];
+        let quirks = Quirks {
+            shift_uses_vy: true,
+            ..none()
+        };
+
+        let system = run(none(), &rom, 3);
+        assert_eq!((system.register(1), system.register(0xF)), (0x40, 1));
+        let system = run(quirks, &rom, 3);
+        assert_eq!((system.register(1), system.register(0xF)), (0x01, 0));
+
+        // LD V1, 0x81; LD V2, 0x82; SHL V1, V2
+        let rom = [0x61, 0x81, 0x62, 0x82, 0x81, 0x2E];
+        let system = run(none(), &rom, 3);
+        assert_eq!((system.register(1), system.register(0xF)), (0x02, 1));
+        let system = run(quirks, &rom, 3);
+        assert_eq!((system.register(1), system.register(0xF)), (0x04, 1));
+    }
+
+    #[test]
+    fn vf_reset() {
+        let quirks = Quirks {
+            vf_reset: true,
+            ..none()
+        };
+
+        // LD VF, 5; LD V1, 0x0C; OR/AND/XOR V1, V1
+        for &op in [0x11, 0x12, 0x13].iter() {
+            let rom = [0x6F, 0x05, 0x61, 0x0C, 0x81, op];
+            assert_eq!(run(none(), &rom, 3).register(0xF), 5);
+            assert_eq!(run(quirks, &rom, 3).register(0xF), 0);
+        }
+    }
+
+    #[test]
+    fn jump_with_vx() {
+        // LD V0, 0x10; LD V3, 0x20; JP V0, 0x320
+        let rom = [0x60, 0x10, 0x63, 0x20, 0xB3, 0x20];
+        let quirks = Quirks {
+            jump_with_vx: true,
+            ..none()
+        };
+
+        assert_eq!(run(none(), &rom, 3).pc(), 0x330);
+        assert_eq!(run(quirks, &rom, 3).pc(), 0x340);
+    }
+
+    #[test]
+    fn load_store_increments_i() {
+        // LD I, 0x300; LD [I], V2; LD V2, [I]
+        let rom = [0xA3, 0x00, 0xF2, 0x55, 0xF2, 0x65];
+        let by_x = Quirks {
+            load_store_increments_i_by_x: true,
+            ..none()
+        };
+        let by_x_plus_one = Quirks {
+            load_store_increments_i: true,
+            ..none()
+        };
+        let both = Quirks {
+            load_store_increments_i: true,
+            ..by_x
+        };
+
+        assert_eq!(run(none(), &rom, 3).index(), 0x300);
+        assert_eq!(run(by_x, &rom, 3).index(), 0x304);
+        assert_eq!(run(by_x_plus_one, &rom, 3).index(), 0x306);
+        assert_eq!(run(both, &rom, 3).index(), 0x306);
+        assert_eq!(run(Quirks::chip48(), &rom, 3).index(), 0x304);
+    }
+
+    #[test]
+    fn display_wait() {
+        // LD I, 0; DRW V0, V0, 1; ADD V1, 1
+        let rom = [0xA0, 0x00, 0xD0, 0x01, 0x71, 0x01];
+        let quirks = Quirks {
+            display_wait: true,
+            ..none()
+        };
+
+        assert_eq!(run(none(), &rom, 3).register(1), 1);
+
+        let mut system = run(quirks, &rom, 3);
+        assert_eq!(system.register(1), 0);
+        assert!(!system.ready());
+        system.run_frame().unwrap();
+        system.step().unwrap();
+        assert_eq!(system.register(1), 1);
+    }
+
+    #[test]
+    fn clip_sprites() {
+        // LD V0, 62; LD V1, 30; LD I, 0x20A; DRW V0, V1, 3; DB 0xFF, 0xFF, 0xFF
+        let rom = [
+            0x60, 62, 0x61, 30, 0xA2, 0x0A, 0xD0, 0x13, 0x00, 0x00, 0xFF, 0xFF, 0xFF,
+        ];
+        let quirks = Quirks {
+            clip_sprites: true,
+            ..none()
+        };
+
+        let wrapped = run(none(), &rom, 4);
+        let clipped = run(quirks, &rom, 4);
+        for &(x, y) in [(62, 30), (63, 31)].iter() {
+            assert_eq!(wrapped.pixel(x, y), 1);
+            assert_eq!(clipped.pixel(x, y), 1);
+        }
+        for &(x, y) in [(0, 30), (5, 31), (62, 0), (5, 0)].iter() {
+            assert_eq!(wrapped.pixel(x, y), 1, "({}, {})", x, y);
+            assert_eq!(clipped.pixel(x, y), 0, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn presets_by_name() {
+        assert_eq!(Quirks::from_name("vip"), Some(Quirks::cosmac_vip()));
+        assert_eq!(Quirks::from_name("chip48"), Some(Quirks::chip48()));
+        assert_eq!(Quirks::from_name("schip"), Some(Quirks::super_chip()));
+        assert_eq!(Quirks::from_name("xo"), Some(Quirks::xo_chip()));
+        assert_eq!(Quirks::from_name("octo"), None);
+        assert_ne!(Quirks::chip48(), Quirks::super_chip());
+    }
+}
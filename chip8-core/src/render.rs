@@ -0,0 +1,112 @@
+use crate::palette::Palette;
+use crate::png;
+use crate::Chip8;
+
+/* Turns the machine's packed bitplanes into RGBA pixels, in the colours of a palette.
+ * The machine itself never deals with colours, so this only needs to run when a frame
+ * is actually presented, and the same machine can be drawn in any palette. */
+pub struct Renderer {
+    palette: Palette,
+    rgba: Vec<u8>,
+}
+
+impl Renderer {
+    pub fn new(palette: Palette) -> Renderer {
+        Renderer {
+            palette,
+            rgba: Vec::new(),
+        }
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    /* The screen as display_width x display_height RGBA pixels, row by row. The buffer
+     * is reused between calls. */
+    pub fn render(&mut self, system: &Chip8) -> &[u8] {
+        let display = &system.io.display;
+        let (w, h) = display.size();
+
+        self.rgba.clear();
+        self.rgba.reserve(w * h * 4);
+
+        for y in 0..h {
+            let (plane1, plane2) = (display.row(0, y), display.row(1, y));
+
+            for bit in ((128 - w)..128).rev() {
+                let planes = ((plane1 >> bit) & 1) | ((plane2 >> bit) & 1) << 1;
+                self.rgba
+                    .extend_from_slice(&self.palette.colours[planes as usize]);
+            }
+        }
+
+        &self.rgba
+    }
+
+    /* The screen as a PNG file, with every pixel scaled up to a scale x scale square.
+     * A scale of 1 (or 0) gives the native resolution. */
+    pub fn screenshot(&mut self, system: &Chip8, scale: usize) -> Vec<u8> {
+        let scale = scale.max(1);
+        let (w, h) = (system.display_width(), system.display_height());
+        let rgba = self.render(system);
+
+        let mut scaled = Vec::with_capacity(w * h * scale * scale * 4);
+        for row in rgba.chunks(w * 4) {
+            let mut scaled_row = Vec::with_capacity(w * scale * 4);
+            for colour in row.chunks(4) {
+                for _ in 0..scale {
+                    scaled_row.extend_from_slice(colour);
+                }
+            }
+
+            for _ in 0..scale {
+                scaled.extend_from_slice(&scaled_row);
+            }
+        }
+
+        png::encode(w * scale, h * scale, &scaled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Quirks;
+
+    #[test]
+    fn screenshots_are_scaled_renders() {
+        let mut system = Chip8::new(600, Quirks::default());
+        // LD V0, 1; LD F, V0; DRW V0, V0, 5
+        system
+            .load_rom_bytes(&[0x60, 0x01, 0xF0, 0x29, 0xD0, 0x05])
+            .unwrap();
+        for _ in 0..3 {
+            system.step().unwrap();
+        }
+
+        let palette = Palette::from_name("amber").unwrap();
+        let mut renderer = Renderer::new(palette);
+        let rgba = renderer.render(&system).to_vec();
+        assert_eq!(&rgba[..4], &palette.background());
+        // The top of the 1 is a single pixel at (3, 1)
+        assert_eq!(&rgba[(4 * (64 + 3))..(4 * (64 + 4))], &palette.foreground());
+
+        assert_eq!(renderer.screenshot(&system, 1), png::encode(64, 32, &rgba));
+        assert_eq!(renderer.screenshot(&system, 0), png::encode(64, 32, &rgba));
+
+        let mut scaled = Vec::new();
+        for row in rgba.chunks(64 * 4) {
+            let row: Vec<u8> = row.chunks(4).flat_map(|pixel| pixel.repeat(3)).collect();
+            scaled.extend(row.repeat(3));
+        }
+        assert_eq!(
+            renderer.screenshot(&system, 3),
+            png::encode(192, 96, &scaled)
+        );
+    }
+}
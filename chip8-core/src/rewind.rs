@@ -0,0 +1,248 @@
+use crate::Chip8;
+use std::collections::VecDeque;
+
+/* A history of recent machine states for running a game backwards.
+ *
+ * Only the newest state is kept whole. Each older one is stored as the difference from
+ * the state after it: the two save states XORed together, which is almost all zeros
+ * from one frame to the next, then run-length encoded. A few seconds of history take
+ * up little more memory than a single save state. */
+pub struct Rewind {
+    // Number of earlier states kept
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    // Oldest first; applying the last delta to latest gives the state before it
+    deltas: VecDeque<Delta>,
+}
+
+enum Delta {
+    // Run-length encoded XOR of two states of the same size
+    Xor(Vec<u8>),
+    // States change size when the resolution or memory size changes
+    Full(Vec<u8>),
+}
+
+impl Rewind {
+    // Keep up to capacity earlier states, e.g. 60 per second of history
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            capacity,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    // Record the machine's current state as the newest one
+    pub fn capture(&mut self, system: &Chip8) {
+        let state = system.save_state();
+
+        if let Some(previous) = self.latest.take() {
+            let delta = if previous.len() == state.len() {
+                Delta::Xor(compress(&xor(&previous, &state)))
+            } else {
+                Delta::Full(previous)
+            };
+
+            self.deltas.push_back(delta);
+            if self.deltas.len() > self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+
+        self.latest = Some(state);
+    }
+
+    /* Put the machine back to the state before the newest one, which then becomes the
+     * newest. Returns false when there's no more history to go back to. */
+    pub fn step_back(&mut self, system: &mut Chip8) -> bool {
+        let (latest, delta) = match (self.latest.take(), self.deltas.pop_back()) {
+            (Some(latest), Some(delta)) => (latest, delta),
+            (latest, _) => {
+                self.latest = latest;
+                return false;
+            }
+        };
+
+        let previous = match delta {
+            Delta::Xor(delta) => xor(&latest, &decompress(&delta, latest.len())),
+            Delta::Full(previous) => previous,
+        };
+
+        let restored = system.load_state(&previous).is_ok();
+        self.latest = Some(previous);
+
+        restored
+    }
+
+    // Number of frames that can currently be stepped back through
+    pub fn frames(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(a, b)| a ^ b).collect()
+}
+
+/* Encode as alternating runs: a count of zero bytes, then a count of literal bytes
+ * followed by the bytes themselves. Counts are variable length so long runs of zeros
+ * cost only a few bytes. */
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        let zeros = data[i..].iter().take_while(|&&byte| byte == 0).count();
+        i += zeros;
+
+        let literals = data[i..].iter().take_while(|&&byte| byte != 0).count();
+        write_count(&mut output, zeros);
+        write_count(&mut output, literals);
+        output.extend_from_slice(&data[i..(i + literals)]);
+        i += literals;
+    }
+
+    output
+}
+
+fn decompress(data: &[u8], len: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(len);
+    let mut i = 0;
+
+    while i < data.len() {
+        let zeros = read_count(data, &mut i);
+        let literals = read_count(data, &mut i);
+
+        output.resize(output.len() + zeros, 0);
+        output.extend_from_slice(&data[i..(i + literals)]);
+        i += literals;
+    }
+
+    output.resize(len, 0);
+    output
+}
+
+// Seven bits per byte, with the high bit set on every byte but the last
+fn write_count(output: &mut Vec<u8>, mut count: usize) {
+    while count >= 0x80 {
+        output.push((count & 0x7F) as u8 | 0x80);
+        count >>= 7;
+    }
+
+    output.push(count as u8);
+}
+
+fn read_count(data: &[u8], i: &mut usize) -> usize {
+    let mut count = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = data[*i];
+        *i += 1;
+        count |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return count;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::Quirks;
+
+    // Counts up in V0 and draws it, switching to high resolution after 20 frames
+    const PROGRAM: &str = "
+        loop:
+            ADD V0, 1
+            LD F, V0
+            DRW V1, V1, 5
+            LD V2, DT
+            SE V2, 0
+            JP loop
+            LD V2, 1
+            LD DT, V2
+            ADD V3, 1
+            SE V3, 20
+            JP loop
+            HIGH
+            JP loop
+    ";
+
+    fn machine() -> Chip8 {
+        let mut system = Chip8::with_seed(600, Quirks::super_chip(), 0);
+        system
+            .load_rom_bytes(&assemble(PROGRAM, "test.asm").unwrap())
+            .unwrap();
+        system
+    }
+
+    #[test]
+    fn steps_back_through_every_captured_frame() {
+        let mut system = machine();
+        let mut rewind = Rewind::new(100);
+        let mut states = Vec::new();
+
+        for _ in 0..40 {
+            system.run_frame().unwrap();
+            rewind.capture(&system);
+            states.push(system.save_state());
+        }
+        assert_eq!(rewind.frames(), 39);
+        assert!(system.hires());
+
+        // Past the resolution switch, where the states change size
+        for expected in states.iter().rev().skip(1) {
+            assert!(rewind.step_back(&mut system));
+            assert_eq!(&system.save_state(), expected);
+        }
+        assert!(!rewind.step_back(&mut system));
+        assert_eq!(system.save_state(), states[0]);
+    }
+
+    #[test]
+    fn keeps_only_capacity_frames() {
+        let mut system = machine();
+        let mut rewind = Rewind::new(5);
+        let mut states = Vec::new();
+
+        for _ in 0..10 {
+            system.run_frame().unwrap();
+            rewind.capture(&system);
+            states.push(system.save_state());
+        }
+        assert_eq!(rewind.frames(), 5);
+
+        for _ in 0..5 {
+            assert!(rewind.step_back(&mut system));
+        }
+        assert!(!rewind.step_back(&mut system));
+        assert_eq!(system.save_state(), states[4]);
+
+        rewind.clear();
+        assert_eq!(rewind.frames(), 0);
+        assert!(!rewind.step_back(&mut system));
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        let mut data = vec![0; 1000];
+        data[0] = 1;
+        data[500..700].iter_mut().for_each(|byte| *byte = 0xAA);
+        data[999] = 2;
+
+        for data in [data, vec![0; 70000], vec![7; 300], Vec::new()].iter() {
+            let compressed = compress(data);
+            assert_eq!(&decompress(&compressed, data.len()), data);
+        }
+        assert!(compress(&[0; 70000]).len() <= 4);
+    }
+}
@@ -0,0 +1,68 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/* The random number generator behind RND, a fast, good quality xorshift64*. It's owned
+ * by the machine so that a run can be repeated exactly from the same seed, and its state
+ * is part of save states. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rng {
+    seed: u64,
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        let mut rng = Rng { seed, state: 0 };
+        rng.reseed(seed);
+
+        rng
+    }
+
+    // Seed from the clock, for when runs don't need to be repeatable
+    pub fn from_time() -> Rng {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or(0);
+
+        Rng::new(nanos)
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        // xorshift never leaves zero, so that state is avoided
+        self.state = splitmix64(seed).max(1);
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
+    // The part that changes while running, for save states
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    // Returns None for a state the generator could never be in
+    pub fn restore(seed: u64, state: u64) -> Option<Rng> {
+        if state == 0 {
+            return None;
+        }
+
+        Some(Rng { seed, state })
+    }
+}
+
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
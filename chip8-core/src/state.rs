@@ -0,0 +1,361 @@
+use crate::display::Display;
+use crate::rng::Rng;
+use crate::{
+    Chip8, CpuState, IOState, Quirks, StateError, MEMORY_SIZE, STACK_SIZE, XO_CHIP_MEMORY_SIZE,
+};
+use std::convert::TryInto;
+
+/* Save states are a small binary format: the magic bytes, a version number, then every
+ * part of the machine in a fixed order with integers stored big endian. The version is
+ * bumped whenever the layout changes, and older versions are rejected rather than
+ * misread. */
+const MAGIC: &[u8; 4] = b"C8SS";
+const VERSION: u32 = 6;
+
+impl Chip8 {
+    /* Snapshot the whole machine: registers, timers, stack, memory, display, keys and
+     * settings. The result can be written to disk and given back to load_state. */
+    pub fn save_state(&self) -> Vec<u8> {
+        let (io, cpu) = (&self.io, &self.cpu);
+        let mut state = StateWriter::new();
+
+        state.bytes(MAGIC);
+        state.u32(VERSION);
+
+        state.u8(flags(&[
+            io.xo_chip,
+            io.display.hires(),
+            cpu.waiting,
+            cpu.vblank_wait,
+            cpu.exited,
+        ]));
+        state.u8(quirk_flags(&self.quirks));
+        state.u32(cpu.clock_speed as u32);
+        state.u64(cpu.cycles);
+        state.u64(cpu.frames);
+        state.u64(cpu.time);
+        state.u64(cpu.time_carry as u64);
+
+        state.u64(cpu.rng.seed());
+        state.u64(cpu.rng.state());
+
+        for register in cpu.registers.iter() {
+            state.u8(*register as u8);
+        }
+        state.u32(cpu.pc as u32);
+        state.u32(cpu.load_address as u32);
+        state.u32(cpu.index as u32);
+        state.u8(cpu.delay_timer as u8);
+        state.u8(cpu.sound_timer as u8);
+
+        state.u8(io.stack.len() as u8);
+        for address in io.stack.iter() {
+            state.u32(*address as u32);
+        }
+
+        state.bytes(&io.key_inputs);
+        state.bytes(&io.rpl_flags);
+        state.bytes(&io.audio_pattern);
+        state.u8(io.pitch);
+
+        state.u32(io.memory.len() as u32);
+        state.bytes(&io.memory);
+
+        state.u8(io.display.planes());
+        state.bytes(&io.display.to_bytes());
+
+        state.buffer
+    }
+
+    /* Restore a snapshot made by save_state. The machine is left untouched if the state
+     * can't be read. A machine that had halted on an error is resumed, so loading an
+     * earlier state is a way to recover from a crash. */
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(state);
+
+        if state.bytes(MAGIC.len())? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+
+        let version = state.u32()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion { version });
+        }
+
+        let flags = state.u8()?;
+        let flag = |n: u8| flags & (1 << n) > 0;
+        let (xo_chip, hires, waiting, vblank_wait, exited) =
+            (flag(0), flag(1), flag(2), flag(3), flag(4));
+        let quirks = read_quirk_flags(state.u8()?);
+        let clock_speed = state.u32()? as usize;
+        let cycles = state.u64()?;
+        let frames = state.u64()?;
+        let time = state.u64()?;
+        let time_carry = u128::from(state.u64()?);
+
+        let seed = state.u64()?;
+        let rng = Rng::restore(seed, state.u64()?).ok_or(StateError::Corrupt)?;
+
+        let mut registers = [0; 16];
+        for register in registers.iter_mut() {
+            *register = state.u8()? as usize;
+        }
+        let pc = state.u32()? as usize;
+        let load_address = state.u32()? as usize;
+        let index = state.u32()? as usize;
+        let delay_timer = state.u8()? as usize;
+        let sound_timer = state.u8()? as usize;
+
+        let stack_len = state.u8()? as usize;
+        if stack_len > STACK_SIZE {
+            return Err(StateError::Corrupt);
+        }
+        let mut stack = Vec::with_capacity(stack_len);
+        for _ in 0..stack_len {
+            stack.push(state.u32()? as usize);
+        }
+
+        let key_inputs = state.array()?;
+        let rpl_flags = state.array()?;
+        let audio_pattern = state.array()?;
+        let pitch = state.u8()?;
+
+        let memory_size = state.u32()? as usize;
+        let expected_size = if xo_chip {
+            XO_CHIP_MEMORY_SIZE
+        } else {
+            MEMORY_SIZE
+        };
+        if memory_size != expected_size {
+            return Err(StateError::Corrupt);
+        }
+        let memory = state.bytes(memory_size)?.to_vec();
+
+        let planes = state.u8()?;
+        // Two planes, with a bit for every pixel
+        let (w, h) = Display::resolution(hires);
+        let pixels = state.bytes(2 * h * w / 8)?;
+        let display = Display::from_bytes(hires, planes, pixels).ok_or(StateError::Corrupt)?;
+
+        if !state.finished() || clock_speed == 0 {
+            return Err(StateError::Corrupt);
+        }
+
+        self.io = IOState {
+            key_inputs,
+            display,
+            memory,
+            stack,
+            rpl_flags,
+            audio_pattern,
+            pitch,
+            xo_chip,
+        };
+
+        self.cpu = CpuState {
+            registers,
+            pc,
+            load_address,
+            index,
+            sound_timer,
+            delay_timer,
+            waiting,
+            vblank_wait,
+            exited,
+            halted: None,
+            clock_speed,
+            cycles,
+            frames,
+            time,
+            time_carry,
+            rng,
+        };
+
+        self.quirks = quirks;
+
+        Ok(())
+    }
+}
+
+fn flags(values: &[bool]) -> u8 {
+    values
+        .iter()
+        .enumerate()
+        .fold(0, |flags, (bit, value)| flags | ((*value as u8) << bit))
+}
+
+fn quirk_flags(quirks: &Quirks) -> u8 {
+    flags(&[
+        quirks.shift_uses_vy,
+        quirks.load_store_increments_i,
+        quirks.jump_with_vx,
+        quirks.vf_reset,
+        quirks.clip_sprites,
+        quirks.display_wait,
+        quirks.load_store_increments_i_by_x,
+    ])
+}
+
+fn read_quirk_flags(flags: u8) -> Quirks {
+    let bit = |n: u8| flags & (1 << n) > 0;
+
+    Quirks {
+        shift_uses_vy: bit(0),
+        load_store_increments_i: bit(1),
+        jump_with_vx: bit(2),
+        vf_reset: bit(3),
+        clip_sprites: bit(4),
+        display_wait: bit(5),
+        load_store_increments_i_by_x: bit(6),
+    }
+}
+
+struct StateWriter {
+    buffer: Vec<u8>,
+}
+
+impl StateWriter {
+    fn new() -> StateWriter {
+        StateWriter { buffer: Vec::new() }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_be_bytes());
+    }
+}
+
+struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position + len;
+        if end > self.data.len() {
+            return Err(StateError::Truncated);
+        }
+
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+
+        Ok(bytes)
+    }
+
+    fn array(&mut self) -> Result<[u8; 16], StateError> {
+        Ok(self.bytes(16)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn finished(&self) -> bool {
+        self.position == self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // Draws random sprites in both planes, keeping the timers, stack and memory busy
+    const PROGRAM: &str = "
+            HIGH
+        loop:
+            RND V0, 0x7F
+            RND V1, 0x3F
+            LD I, sprite
+            PLANE 3
+            DRW V0, V1, 4
+            LD DT, V0
+            LD ST, V1
+            CALL store
+            JP loop
+        store:
+            ADD V2, 1
+            LD I, 0x400
+            LD [I], V2
+            RET
+        sprite:
+            DB 0xF0, 0x90, 0x90, 0xF0
+    ";
+
+    fn machine() -> Chip8 {
+        let mut system = Chip8::with_seed(600, Quirks::xo_chip(), 1234);
+        system.enable_xo_chip();
+        system
+            .load_rom_bytes(&assemble(PROGRAM, "test.asm").unwrap())
+            .unwrap();
+        system
+    }
+
+    #[test]
+    fn loaded_state_runs_the_same() {
+        let mut system = machine();
+        for _ in 0..10 {
+            system.run_frame().unwrap();
+        }
+        system.press_key(5);
+        let state = system.save_state();
+
+        let mut restored = Chip8::new(60, Quirks::default());
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+
+        for _ in 0..10 {
+            system.run_frame().unwrap();
+            restored.run_frame().unwrap();
+        }
+        assert_eq!(restored.save_state(), system.save_state());
+        assert!(restored.key_pressed(5));
+    }
+
+    #[test]
+    fn bad_states_are_rejected() {
+        let mut system = machine();
+        system.run_frame().unwrap();
+        let state = system.save_state();
+        let mut other = Chip8::new(600, Quirks::default());
+        let untouched = other.save_state();
+
+        assert_eq!(other.load_state(b"nope"), Err(StateError::BadMagic));
+
+        let mut old = state.clone();
+        old[4..8].copy_from_slice(&2u32.to_be_bytes());
+        assert_eq!(
+            other.load_state(&old),
+            Err(StateError::UnsupportedVersion { version: 2 })
+        );
+
+        for len in MAGIC.len()..state.len() {
+            assert_eq!(other.load_state(&state[..len]), Err(StateError::Truncated));
+        }
+        assert_eq!(other.save_state(), untouched);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::Instruction;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/* Writes a line for every instruction the machine executes, for finding out what a
+ * program did after the fact. Lines are written before the instruction runs, so they
+ * show the state it started from:
+ *
+ *     <cycle> <pc> <opcode> <V0-VF> <I> <mnemonic>
+ *     1234 0200 6A02 00000000000000000000000000000000 0000 LD VA, 0x02
+ *
+ * The cycle count is decimal and everything else is hexadecimal. The opcode is four
+ * digits, or eight for XO-CHIP's long load, and V0-VF are two digits each. The mnemonic
+ * is in the disassembler's syntax and is last, so the other fields can be split on
+ * whitespace. */
+pub struct Tracer {
+    output: Box<dyn Write>,
+    // Only instructions at these addresses are traced
+    range: Option<RangeInclusive<usize>>,
+    // The most lines to write, after which tracing stops
+    limit: Option<u64>,
+    lines: u64,
+    // The first write error, which stops tracing
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>) -> Tracer {
+        Tracer {
+            output,
+            range: None,
+            limit: None,
+            lines: 0,
+            error: None,
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Tracer> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(file))))
+    }
+
+    pub fn set_range(&mut self, range: RangeInclusive<usize>) {
+        self.range = Some(range);
+    }
+
+    pub fn set_limit(&mut self, limit: u64) {
+        self.limit = Some(limit);
+    }
+
+    // The number of lines written so far
+    pub fn lines(&self) -> u64 {
+        self.lines
+    }
+
+    // Flush the output, returning the error that stopped tracing if there was one
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.output.flush(),
+        }
+    }
+
+    pub(crate) fn trace(
+        &mut self,
+        cycle: u64,
+        pc: usize,
+        instruction: Instruction,
+        registers: &[usize; 16],
+        index: usize,
+    ) {
+        if self.error.is_some() || self.limit.is_some_and(|limit| self.lines >= limit) {
+            return;
+        }
+
+        if self
+            .range
+            .as_ref()
+            .is_some_and(|range| !range.contains(&pc))
+        {
+            return;
+        }
+
+        let opcode: String = instruction
+            .bytes()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        let registers: String = registers
+            .iter()
+            .map(|&value| format!("{:02X}", value))
+            .collect();
+
+        let result = writeln!(
+            self.output,
+            "{} {:04X} {} {} {:04X} {}",
+            cycle, pc, opcode, registers, index, instruction
+        );
+
+        match result {
+            Ok(()) => self.lines += 1,
+            Err(error) => self.error = Some(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Chip8, Quirks};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Output the test can still read after handing it to the tracer
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // LD VA, 2; LD I, long 0x1234; ADD VA, 1; JP 0x200
+    const ROM: [u8; 10] = [0x6A, 0x02, 0xF0, 0x00, 0x12, 0x34, 0x7A, 0x01, 0x12, 0x00];
+
+    // Trace the first steps of ROM, returning the lines written
+    fn trace(steps: usize, setup: impl FnOnce(&mut Tracer)) -> Vec<String> {
+        let output = Shared::default();
+        let mut tracer = Tracer::new(Box::new(output.clone()));
+        setup(&mut tracer);
+
+        let mut system = Chip8::with_seed(600, Quirks::default(), 0);
+        system.enable_xo_chip();
+        system.load_rom_bytes(&ROM).unwrap();
+        system.set_tracer(tracer);
+        system.run_cycles(steps as u64).unwrap();
+
+        let tracer = system.take_tracer().unwrap();
+        let lines = tracer.lines();
+        tracer.finish().unwrap();
+
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        let lines_written: Vec<String> = text.lines().map(str::to_string).collect();
+        assert_eq!(lines_written.len() as u64, lines);
+        lines_written
+    }
+
+    #[test]
+    fn lines_show_the_state_before_each_instruction() {
+        let after = format!("{}02{}", "00".repeat(10), "00".repeat(5));
+
+        assert_eq!(
+            trace(4, |_| {}),
+            vec![
+                "0 0200 6A02 00000000000000000000000000000000 0000 LD VA, 0x02".to_string(),
+                format!("1 0202 F0001234 {} 0000 LD I, long 0x1234", after),
+                format!("2 0206 7A01 {} 1234 ADD VA, 0x01", after),
+                format!(
+                    "3 0208 1200 {}03{} 1234 JP 0x200",
+                    "00".repeat(10),
+                    "00".repeat(5)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_addresses_in_range_are_traced() {
+        let lines = trace(8, |tracer| tracer.set_range(0x202..=0x206));
+        let addresses: Vec<&str> = lines
+            .iter()
+            .map(|line| line.split(' ').nth(1).unwrap())
+            .collect();
+        assert_eq!(addresses, vec!["0202", "0206", "0202", "0206"]);
+    }
+
+    #[test]
+    fn tracing_stops_at_the_limit() {
+        let lines = trace(8, |tracer| tracer.set_limit(3));
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].starts_with("2 0206 "));
+
+        let lines = trace(8, |tracer| {
+            tracer.set_range(0x208..=0x208);
+            tracer.set_limit(1);
+        });
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("3 0208 "));
+    }
+
+    #[test]
+    fn write_errors_stop_tracing() {
+        let mut tracer = Tracer::new(Box::new(Broken));
+        tracer.trace(0, 0x200, Instruction::Cls, &[0; 16], 0);
+        tracer.trace(1, 0x202, Instruction::Cls, &[0; 16], 0);
+
+        assert_eq!(tracer.lines(), 0);
+        assert_eq!(tracer.finish().unwrap_err().to_string(), "disk full");
+    }
+}
@@ -1,146 +1,1051 @@
-use ggez::conf::{WindowMode, WindowSetup};
-use ggez::graphics::{self, FilterMode};
+use ggez::audio::{self, SoundSource};
+use ggez::conf::{FullscreenType, WindowMode, WindowSetup};
+use ggez::graphics::{self, DrawParam, FilterMode, Image, Rect};
+use ggez::input::keyboard;
 use ggez::nalgebra as na;
-use ggez::{event, Context, GameResult};
+use ggez::{event, timer, Context, GameError, GameResult};
 use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub mod chip8;
-use chip8::Chip8;
+use chip8_core::audio::{Tone, Waveform};
+use chip8_core::cli::{flag_value, parse_number};
+use chip8_core::debugger::Debugger;
+use chip8_core::gdb::GdbServer;
+use chip8_core::movie::{Movie, Playback};
+use chip8_core::palette::{self, Palette};
+use chip8_core::{Chip8, Chip8Error, Quirks, Renderer, Rewind, Tracer};
+use phosphor::Phosphor;
+
+mod overlay;
+mod phosphor;
 
 fn main() -> GameResult {
+    let args: Vec<String> = env::args().collect();
+
+    // The window starts out fitting the low resolution screen at --scale
+    let scale = match flag_value(&args, "--scale") {
+        Some(val) => match val.parse::<u32>() {
+            Ok(scale) if scale > 0 => scale as f32,
+            _ => {
+                return Err(GameError::ConfigError(format!("Invalid scale: {}", val)));
+            }
+        },
+        None => DEFAULT_SCALE,
+    };
+
     let window_setup = WindowSetup::default().title("chip8.rs");
-    let window_mode = WindowMode::default().dimensions(64.0, 32.0);
+    let window_mode = WindowMode::default()
+        .dimensions(LORES_WIDTH * scale, LORES_HEIGHT * scale)
+        .min_dimensions(LORES_WIDTH, LORES_HEIGHT)
+        .resizable(true);
     let cb = ggez::ContextBuilder::new("chip8.rs", "alex garrett")
         .window_setup(window_setup)
         .window_mode(window_mode);
     let (mut ctx, mut event_loop) = cb.build()?;
 
     let mut state = MainState::new(&mut ctx)?;
-
-    graphics::set_drawable_size(&mut ctx, 320.0, 160.0)?;
     event::run(&mut ctx, &mut event_loop, &mut state)
 }
 
+// Size of the low resolution screen, which sets the shape of the window
+const LORES_WIDTH: f32 = 64.0;
+const LORES_HEIGHT: f32 = 32.0;
+
+/* Window pixels to each low resolution pixel unless --scale says otherwise. An even
+ * scale keeps high resolution pixels whole too. */
+const DEFAULT_SCALE: f32 = 10.0;
+
+// The most emulated time that passes between two updates
+const MAX_FRAME_TIME: Duration = Duration::from_millis(100);
+
+// Seconds of gameplay that can be rewound unless --rewind says otherwise
+const DEFAULT_REWIND_SECONDS: usize = 10;
+
+// Sample rate the beep is generated at
+const SAMPLE_RATE: u32 = 44100;
+
+// Movies are recorded and played back a whole 60 Hz frame at a time
+const FRAME_TIME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+// The keyboard key for each CHIP-8 key, as laid out in key_down_event
+const KEYPAD: [event::KeyCode; 16] = [
+    event::KeyCode::X,
+    event::KeyCode::Key1,
+    event::KeyCode::Key2,
+    event::KeyCode::Key3,
+    event::KeyCode::Q,
+    event::KeyCode::W,
+    event::KeyCode::E,
+    event::KeyCode::A,
+    event::KeyCode::S,
+    event::KeyCode::D,
+    event::KeyCode::Z,
+    event::KeyCode::C,
+    event::KeyCode::Key4,
+    event::KeyCode::R,
+    event::KeyCode::F,
+    event::KeyCode::V,
+];
+
+/* Opens the file given with --trace, applying --trace-range (e.g. 0x200-0x2FF) and
+ * --trace-limit if they're given. */
+fn open_tracer(args: &[String]) -> GameResult<Option<Tracer>> {
+    let path = match flag_value(args, "--trace") {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let mut tracer = Tracer::to_file(path)
+        .map_err(|error| GameError::ResourceLoadError(format!("{}: {}", path, error)))?;
+
+    if let Some(val) = flag_value(args, "--trace-range") {
+        let range = val
+            .split_once('-')
+            .and_then(|(start, end)| Some(parse_number(start)?..=parse_number(end)?));
+        match range {
+            Some(range) => tracer.set_range(range),
+            None => {
+                return Err(GameError::ConfigError(format!(
+                    "Invalid trace range: {} (expected start-end)",
+                    val
+                )));
+            }
+        }
+    }
+
+    if let Some(val) = flag_value(args, "--trace-limit") {
+        match val.parse() {
+            Ok(limit) => tracer.set_limit(limit),
+            Err(_) => {
+                return Err(GameError::ConfigError(format!(
+                    "Invalid trace limit: {}",
+                    val
+                )));
+            }
+        }
+    }
+
+    println!("Tracing to {}", path);
+    Ok(Some(tracer))
+}
+
+/* Builds the palette from --theme, then --fg, --bg and --plane-colours, which give
+ * the colours for plane 2 and both planes as e.g. "#FF0000,#00FF00". Returns it along
+ * with the theme's position in palette::THEMES. */
+fn parse_palette(args: &[String]) -> GameResult<(usize, Palette)> {
+    let theme = match flag_value(args, "--theme") {
+        Some(name) => palette::THEMES
+            .iter()
+            .position(|&theme| theme == name)
+            .ok_or_else(|| {
+                GameError::ConfigError(format!(
+                    "Unknown theme: {} (expected one of {})",
+                    name,
+                    palette::THEMES.join(", ")
+                ))
+            })?,
+        None => 0,
+    };
+    let mut palette = Palette::from_name(palette::THEMES[theme]).unwrap();
+
+    let colour = |flag: &str, text: &str| {
+        palette::parse_colour(text)
+            .ok_or_else(|| GameError::ConfigError(format!("Invalid colour for {}: {}", flag, text)))
+    };
+
+    if let Some(val) = flag_value(args, "--fg") {
+        palette.set_foreground(colour("--fg", val)?);
+    }
+    if let Some(val) = flag_value(args, "--bg") {
+        palette.set_background(colour("--bg", val)?);
+    }
+    if let Some(val) = flag_value(args, "--plane-colours") {
+        match val.split_once(',') {
+            Some((plane2, both)) => {
+                palette.colours[2] = colour("--plane-colours", plane2)?;
+                palette.colours[3] = colour("--plane-colours", both)?;
+            }
+            None => {
+                return Err(GameError::ConfigError(format!(
+                    "Invalid plane colours: {} (expected two colours separated by a comma)",
+                    val
+                )));
+            }
+        }
+    }
+
+    Ok((theme, palette))
+}
+
+/* Debugger commands are read from stdin on their own thread, so the window keeps
+ * updating while waiting for the user to type. */
+fn spawn_command_reader() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    receiver
+}
+
+fn prompt() {
+    print!("(chip8) ");
+    io::stdout().flush().ok();
+}
+
+// How the game screen is scaled to fill the window
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ScaleMode {
+    // The largest whole number of window pixels per screen pixel, so pixels stay even
+    Integer,
+    // As large as fits, set with --fit
+    Fit,
+}
+
 struct MainState {
     system: Chip8,
-    origin: na::Point2<f32>,
-    debug: bool,
-    step: bool,
+    rom_path: String,
+    scale_mode: ScaleMode,
+    // Toggled with F11
+    fullscreen: bool,
+    // Set with -d or --gdb, along with the commands typed at the -d prompt
+    debugger: Option<Debugger>,
+    commands: Option<Receiver<String>>,
+    // Set while the debug overlay is shown, toggled with Tab
+    overlay: bool,
+    // Set with --gdb, and drives the debugger for a connected gdb
+    gdb: Option<GdbServer>,
+    rewind: Rewind,
+    // Set while the rewind key is held
+    rewinding: bool,
+    // The movie being recorded with --record, and where to save it
+    recording: Option<(Movie, String)>,
+    playback: Option<Playback>,
+    // Key changes waiting for the next frame while recording
+    pending_keys: Vec<(usize, bool)>,
+    // Time not yet emulated when running whole frames
+    frame_time: Duration,
+    /* Time since the last rewind state was captured, or while rewinding, not yet
+     * stepped back through */
+    rewind_time: Duration,
+    tone: Tone,
+    /* The beep, looping while the sound timer runs, and the XO-CHIP audio pattern and
+     * playback rate it was made for */
+    beep: Option<(([u8; 16], u64), audio::Source)>,
+    // Toggled with M
+    muted: bool,
+    renderer: Renderer,
+    // Position in palette::THEMES of the theme in use, changed with P
+    theme: usize,
+    // Set with --persistence to fade out erased pixels
+    phosphor: Option<Phosphor>,
+    // Where F12 saves screenshots, the ROM's directory unless --screenshot-dir is given
+    screenshot_dir: PathBuf,
 }
 
 impl MainState {
-    fn new(_ctx: &mut Context) -> GameResult<MainState> {
+    fn new(ctx: &mut Context) -> GameResult<MainState> {
         let args: Vec<String> = env::args().collect();
-        let mut debug = false;
-        if let Some(val) = args.get(3) {
-            if val == "-d" {
-                debug = true;
-            }
-        }
+        let debug = args.iter().any(|arg| arg == "-d");
+        let gdb_port = flag_value(&args, "--gdb");
+        let xo_chip = args.iter().any(|arg| arg == "--xo-chip");
 
         let clock_speed = match args[2].parse() {
+            Ok(0) => {
+                return Err(GameError::ConfigError("Invalid clock speed: 0".to_string()));
+            }
             Ok(val) => val,
             Err(_) => 600,
         };
 
-        let system = Chip8::new(clock_speed);
-        let mut s = MainState {
-            system: system,
-            origin: na::Point2::new(0.0, 0.0),
-            debug: debug,
-            step: false,
+        let quirks = match flag_value(&args, "--quirks") {
+            Some(name) => match Quirks::from_name(name) {
+                Some(quirks) => quirks,
+                None => {
+                    return Err(GameError::ConfigError(format!(
+                        "Unknown quirks profile: {} (expected vip, chip48, schip or xo)",
+                        name
+                    )));
+                }
+            },
+            None if xo_chip => Quirks::xo_chip(),
+            None => Quirks::default(),
         };
 
-        s.system.load_rom(&args[1])?;
+        let rewind_seconds = match flag_value(&args, "--rewind") {
+            Some(val) => match val.parse() {
+                Ok(seconds) => seconds,
+                Err(_) => {
+                    return Err(GameError::ConfigError(format!(
+                        "Invalid rewind length: {}",
+                        val
+                    )));
+                }
+            },
+            None => DEFAULT_REWIND_SECONDS,
+        };
+
+        let (theme, palette) = parse_palette(&args)?;
+        // Given as the fade time in milliseconds
+        let phosphor = match flag_value(&args, "--persistence") {
+            Some(val) => match val.parse() {
+                Ok(millis) => Some(Phosphor::new(Duration::from_millis(millis))),
+                Err(_) => {
+                    return Err(GameError::ConfigError(format!(
+                        "Invalid persistence time: {}",
+                        val
+                    )));
+                }
+            },
+            None => None,
+        };
+        let screenshot_dir = flag_value(&args, "--screenshot-dir").map(PathBuf::from);
+        let scale_mode = if args.iter().any(|arg| arg == "--fit") {
+            ScaleMode::Fit
+        } else {
+            ScaleMode::Integer
+        };
+
+        let mut tone = Tone::default();
+        if let Some(name) = flag_value(&args, "--waveform") {
+            tone.waveform = Waveform::from_name(name).ok_or_else(|| {
+                GameError::ConfigError(format!(
+                    "Unknown waveform: {} (expected square, triangle, sawtooth or sine)",
+                    name
+                ))
+            })?;
+        }
+        if let Some(val) = flag_value(&args, "--frequency") {
+            tone.frequency = match val.parse() {
+                Ok(frequency) if frequency > 0.0 => frequency,
+                _ => {
+                    return Err(GameError::ConfigError(format!(
+                        "Invalid beep frequency: {}",
+                        val
+                    )));
+                }
+            };
+        }
+        // Volume is given as a percentage
+        if let Some(val) = flag_value(&args, "--volume") {
+            tone.volume = match val.parse::<f32>() {
+                Ok(volume) if (0.0..=100.0).contains(&volume) => volume / 100.0,
+                _ => {
+                    return Err(GameError::ConfigError(format!(
+                        "Invalid volume: {} (expected 0 to 100)",
+                        val
+                    )));
+                }
+            };
+        }
+
+        // Movies are played back a frame at a time, which the debugger can't promise
+        if (debug || gdb_port.is_some())
+            && (flag_value(&args, "--record").is_some() || flag_value(&args, "--play").is_some())
+        {
+            return Err(GameError::ConfigError(
+                "The debugger can't be used while recording or playing a movie".to_string(),
+            ));
+        }
+
+        let rom = fs::read(&args[1])
+            .map_err(|error| GameError::ResourceLoadError(format!("{}: {}", args[1], error)))?;
+
+        // Playing a movie sets the machine up the way it was recorded
+        if let Some(path) = flag_value(&args, "--play") {
+            let movie = fs::read_to_string(path)
+                .map_err(|error| error.to_string())
+                .and_then(|text| Movie::parse(&text).map_err(|error| error.to_string()))
+                .map_err(|error| GameError::ResourceLoadError(format!("{}: {}", path, error)))?;
+            let mut system = movie
+                .machine(&rom)
+                .map_err(|error| GameError::ResourceLoadError(format!("{}: {}", path, error)))?;
+            println!("Playing {} ({} frames)", path, movie.end_frame);
+            if let Some(tracer) = open_tracer(&args)? {
+                system.set_tracer(tracer);
+            }
+
+            let mut s = MainState::with_system(system, args[1].clone(), rewind_seconds);
+            s.playback = Some(Playback::new(movie));
+            s.tone = tone;
+            s.theme = theme;
+            s.renderer.set_palette(palette);
+            s.scale_mode = scale_mode;
+            s.phosphor = phosphor;
+            if let Some(dir) = screenshot_dir {
+                s.screenshot_dir = dir;
+            }
+            return Ok(s);
+        }
+
+        let mut system = match flag_value(&args, "--seed") {
+            Some(val) => match val.parse() {
+                Ok(seed) => Chip8::with_seed(clock_speed, quirks, seed),
+                Err(_) => {
+                    return Err(GameError::ConfigError(format!("Invalid seed: {}", val)));
+                }
+            },
+            None => Chip8::new(clock_speed, quirks),
+        };
+        if xo_chip {
+            system.enable_xo_chip();
+        }
+
+        let mut s = MainState::with_system(system, args[1].clone(), rewind_seconds);
+        s.tone = tone;
+        s.theme = theme;
+        s.renderer.set_palette(palette);
+        s.scale_mode = scale_mode;
+        s.phosphor = phosphor;
+        if let Some(dir) = screenshot_dir {
+            s.screenshot_dir = dir;
+        }
+
+        if let Some(val) = flag_value(&args, "--load-address") {
+            match parse_number(val) {
+                Some(address) => s.system.set_load_address(address),
+                None => {
+                    return Err(GameError::ConfigError(format!(
+                        "Invalid load address: {}",
+                        val
+                    )));
+                }
+            }
+        }
+
+        let rom_len = s
+            .system
+            .load_rom_bytes(&rom)
+            .map_err(|error| GameError::ResourceLoadError(format!("{}: {}", args[1], error)))?;
+        println!("Loaded {} ({} bytes)", args[1], rom_len);
+        println!("Random seed: {}", s.system.seed());
+
+        if let Some(tracer) = open_tracer(&args)? {
+            s.system.set_tracer(tracer);
+        }
+
+        if let Some(path) = flag_value(&args, "--record") {
+            s.recording = Some((Movie::new(&s.system, &rom), path.to_string()));
+            println!("Recording to {}", path);
+        }
+
+        if debug {
+            println!(
+                "Debugger started, paused at {:#05X}. Type help for commands.",
+                s.system.pc()
+            );
+            s.debugger = Some(Debugger::new());
+            s.commands = Some(spawn_command_reader());
+            prompt();
+        }
+
+        if let Some(val) = gdb_port {
+            let port: u16 = val
+                .parse()
+                .map_err(|_| GameError::ConfigError(format!("Invalid gdb port: {}", val)))?;
+            let server = GdbServer::bind(("127.0.0.1", port)).map_err(|error| {
+                GameError::ConfigError(format!(
+                    "Couldn't listen for gdb on port {}: {}",
+                    port, error
+                ))
+            })?;
+
+            // The machine stays paused until gdb connects and continues it
+            println!("Waiting for gdb on 127.0.0.1:{}", port);
+            s.gdb = Some(server);
+            if s.debugger.is_none() {
+                s.debugger = Some(Debugger::new());
+            }
+        }
+
+        if s.debugger.is_some() {
+            s.set_overlay(ctx, true)?;
+        }
 
         Ok(s)
     }
+
+    fn with_system(system: Chip8, rom_path: String, rewind_seconds: usize) -> MainState {
+        let screenshot_dir = Path::new(&rom_path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        MainState {
+            system,
+            rom_path,
+            scale_mode: ScaleMode::Integer,
+            fullscreen: false,
+            debugger: None,
+            commands: None,
+            overlay: false,
+            gdb: None,
+            rewind: Rewind::new(rewind_seconds * 60),
+            rewinding: false,
+            recording: None,
+            playback: None,
+            pending_keys: Vec::new(),
+            frame_time: Duration::from_secs(0),
+            rewind_time: Duration::from_secs(0),
+            tone: Tone::default(),
+            beep: None,
+            muted: false,
+            renderer: Renderer::new(Palette::default()),
+            theme: 0,
+            phosphor: None,
+            screenshot_dir,
+        }
+    }
+
+    /* The window grows to fit the overlay beside the game screen while it's shown, and
+     * shrinks back to the game screen's shape when it's hidden. In fullscreen the
+     * overlay takes space from the game screen instead. */
+    fn set_overlay(&mut self, ctx: &mut Context, visible: bool) -> GameResult {
+        if visible == self.overlay {
+            return Ok(());
+        }
+        self.overlay = visible;
+
+        if self.fullscreen {
+            return Ok(());
+        }
+
+        let (width, height) = graphics::drawable_size(ctx);
+        let (width, height) = if visible {
+            (width + overlay::WIDTH, height.max(overlay::HEIGHT))
+        } else {
+            let width = (width - overlay::WIDTH).max(LORES_WIDTH);
+            (width, width * LORES_HEIGHT / LORES_WIDTH)
+        };
+
+        graphics::set_drawable_size(ctx, width, height)?;
+        graphics::set_screen_coordinates(ctx, Rect::new(0.0, 0.0, width, height))
+    }
+
+    fn toggle_fullscreen(&mut self, ctx: &mut Context) -> GameResult {
+        self.fullscreen = !self.fullscreen;
+
+        let fullscreen = if self.fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Windowed
+        };
+        graphics::set_fullscreen(ctx, fullscreen)
+    }
+
+    /* Where the game screen goes in the window and how many window pixels each screen
+     * pixel takes up. It's centred in the space left by the overlay, with black bars
+     * around it when that space is a different shape. The scale is worked out from the
+     * current resolution, so switching to high resolution keeps the same size. */
+    fn screen_layout(&self, ctx: &Context) -> (na::Point2<f32>, f32) {
+        let window = graphics::screen_coordinates(ctx);
+        let width = if self.overlay {
+            (window.w - overlay::WIDTH).max(0.0)
+        } else {
+            window.w
+        };
+
+        let screen_width = self.system.display_width() as f32;
+        let screen_height = self.system.display_height() as f32;
+        let mut scale = (width / screen_width).min(window.h / screen_height);
+        if self.scale_mode == ScaleMode::Integer {
+            scale = scale.floor().max(1.0);
+        }
+
+        let origin = na::Point2::new(
+            ((width - screen_width * scale) / 2.0).max(0.0),
+            ((window.h - screen_height * scale) / 2.0).max(0.0),
+        );
+        (origin, scale)
+    }
+
+    // Rewinding or loading a state would make a movie impossible to play back
+    fn movie_active(&self) -> bool {
+        self.recording.is_some() || self.playback.is_some()
+    }
+
+    fn set_key(&mut self, key: usize, pressed: bool) {
+        if self.playback.is_some() {
+            return;
+        }
+
+        if self.recording.is_some() {
+            self.pending_keys.push((key, pressed));
+        } else if pressed {
+            self.system.press_key(key);
+        } else {
+            self.system.unpress_key(key);
+        }
+    }
+
+    // Run one frame, first applying any key changes from the movie being recorded or played
+    fn run_frame(&mut self) -> Result<(), Chip8Error> {
+        if let Some((movie, _)) = &mut self.recording {
+            let frame = self.system.frame_count();
+
+            for (key, pressed) in self.pending_keys.drain(..) {
+                movie.record(frame, key, pressed);
+                if pressed {
+                    self.system.press_key(key);
+                } else {
+                    self.system.unpress_key(key);
+                }
+            }
+        }
+
+        if let Some(playback) = &mut self.playback {
+            playback.apply(&mut self.system);
+        }
+
+        self.system.run_frame()
+    }
+
+    /* Run the machine for elapsed time, capturing a rewind state after every 1/60 s of
+     * it however often update is called, so the history covers the time it's meant to. */
+    fn run_for(&mut self, elapsed: Duration) -> Result<(), Chip8Error> {
+        let mut remaining = elapsed;
+
+        while self.rewind_time + remaining >= FRAME_TIME {
+            let until_capture = FRAME_TIME - self.rewind_time;
+            self.system.run_for(until_capture)?;
+            self.rewind.capture(&self.system);
+
+            remaining -= until_capture;
+            self.rewind_time = Duration::from_secs(0);
+        }
+
+        self.rewind_time += remaining;
+        self.system.run_for(remaining)
+    }
+
+    fn finish_recording(&mut self) {
+        if let Some((mut movie, path)) = self.recording.take() {
+            movie.end_frame = self.system.frame_count();
+
+            match fs::write(&path, movie.to_string()) {
+                Ok(()) => println!("Saved movie to {}", path),
+                Err(error) => eprintln!("Couldn't save movie to {}: {}", path, error),
+            }
+        }
+    }
+
+    /* Start or stop the beep to match the sound timer. It's silent while the machine
+     * isn't running normally, e.g. while rewinding or paused in the debugger. */
+    fn update_sound(&mut self, ctx: &mut Context) -> GameResult {
+        let paused = self
+            .debugger
+            .as_ref()
+            .is_some_and(|debugger| debugger.paused());
+        let sounding = self.system.sound_timer() > 0
+            && !self.muted
+            && !self.rewinding
+            && !paused
+            && self.system.halted().is_none();
+
+        // XO-CHIP programs can change their sound, so it's made again when they do
+        let key = (
+            *self.system.audio_pattern(),
+            self.system.audio_playback_rate().to_bits(),
+        );
+        let current = matches!(&self.beep, Some((beep_key, _)) if *beep_key == key);
+
+        if sounding && !current {
+            let samples = self.tone.samples(&self.system, SAMPLE_RATE);
+            let wav = chip8_core::audio::encode_wav(&samples, SAMPLE_RATE);
+            let mut source = audio::Source::from_data(ctx, audio::SoundData::from_bytes(&wav))?;
+            source.set_repeat(true);
+            self.beep = Some((key, source));
+        }
+
+        if let Some((_, source)) = &mut self.beep {
+            if sounding && !source.playing() {
+                source.play()?;
+            } else if !sounding && source.playing() {
+                source.stop();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish_trace(&mut self) {
+        if let Some(tracer) = self.system.take_tracer() {
+            let lines = tracer.lines();
+
+            match tracer.finish() {
+                Ok(()) => println!("Traced {} instructions", lines),
+                Err(error) => eprintln!("Couldn't write trace: {}", error),
+            }
+        }
+    }
+
+    // Save states live next to the ROM, e.g. game.ch8.state1
+    fn state_path(&self, slot: usize) -> String {
+        format!("{}.state{}", self.rom_path, slot)
+    }
+
+    fn save_state(&self, slot: usize) {
+        let path = self.state_path(slot);
+
+        match fs::write(&path, self.system.save_state()) {
+            Ok(()) => println!("Saved state to {}", path),
+            Err(error) => eprintln!("Couldn't save state to {}: {}", path, error),
+        }
+    }
+
+    fn load_state(&mut self, slot: usize) {
+        let path = self.state_path(slot);
+        let result = fs::read(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|state| {
+                self.system
+                    .load_state(&state)
+                    .map_err(|error| error.to_string())
+            });
+
+        match result {
+            Ok(()) => println!("Loaded state from {}", path),
+            Err(error) => eprintln!("Couldn't load state from {}: {}", path, error),
+        }
+    }
+
+    /* Save the screen in the current palette as a PNG, named after the ROM and the
+     * time, e.g. game-20240131-154502.png. Phosphor persistence isn't included: the
+     * picture is the machine's screen as it is. */
+    fn save_screenshot(&mut self, scale: usize) {
+        let rom_name = Path::new(&self.rom_path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "screenshot".to_string());
+        let name = format!("{}-{}", rom_name, timestamp());
+
+        // Screenshots taken within the same second get a number on the end
+        let mut path = self.screenshot_dir.join(format!("{}.png", name));
+        let mut count = 1;
+        while path.exists() {
+            count += 1;
+            path = self.screenshot_dir.join(format!("{}-{}.png", name, count));
+        }
+
+        let png = self.renderer.screenshot(&self.system, scale);
+        match fs::write(&path, png) {
+            Ok(()) => println!("Saved screenshot to {}", path.display()),
+            Err(error) => eprintln!("Couldn't save screenshot to {}: {}", path.display(), error),
+        }
+    }
+}
+
+/* The current time in UTC as YYYYMMDD-HHMMSS, for screenshot names. The date comes
+ * from the days since 1970 using Howard Hinnant's civil_from_days algorithm. */
+fn timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+    let (days, time) = (seconds / 86400, seconds % 86400);
+
+    // Count from 0000-03-01 so leap days fall at the end of each 400 year era
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
 }
 
 impl event::EventHandler for MainState {
-    fn update(&mut self, _ctx: &mut Context) -> GameResult {
-        if self.debug {
-            if self.step {
-                self.system.cycle();
-                self.step = false;
+    fn update(&mut self, ctx: &mut Context) -> GameResult {
+        if self.system.exited() {
+            self.finish_recording();
+            self.finish_trace();
+            event::quit(ctx);
+            return Ok(());
+        }
+
+        // Don't try to catch up after long pauses, like while the window is dragged
+        let elapsed = timer::delta(ctx).min(MAX_FRAME_TIME);
+
+        // Go back one frame for every 1/60 s the rewind key is held, so it runs in real time
+        if self.rewinding {
+            self.rewind_time += elapsed;
+            while self.rewind_time >= FRAME_TIME {
+                self.rewind_time -= FRAME_TIME;
+                self.rewind.step_back(&mut self.system);
             }
-        } else {
-            self.system.cycle();
+            return self.update_sound(ctx);
         }
 
-        Ok(())
+        if let Some(debugger) = &mut self.debugger {
+            let frame = self.system.frame_count();
+
+            // The debugger runs the machine itself, and still takes commands after a halt
+            if let Some(commands) = &self.commands {
+                for line in commands.try_iter() {
+                    print!("{}", debugger.command(&mut self.system, &line));
+                    prompt();
+                }
+            }
+
+            if let Some(gdb) = &mut self.gdb {
+                if let Err(error) = gdb.poll(&mut self.system, debugger) {
+                    eprintln!("gdb disconnected: {}", error);
+                }
+            }
+
+            if let Some(stop) = debugger.run_for(&mut self.system, elapsed) {
+                if let Some(gdb) = &mut self.gdb {
+                    if let Err(error) = gdb.stopped(&stop) {
+                        eprintln!("gdb disconnected: {}", error);
+                    }
+                }
+
+                if self.commands.is_some() {
+                    println!("\n{}", stop);
+                    prompt();
+                }
+            }
+
+            // The debugger may stop mid-frame, so states are only captured as frames end
+            if self.system.frame_count() != frame {
+                self.rewind.capture(&self.system);
+            }
+        } else if self.system.halted().is_none() {
+            // A halted machine is left as it is so its last frame stays on screen
+            let result = if self.movie_active() {
+                self.frame_time += elapsed;
+
+                let mut result = Ok(());
+                while self.frame_time >= FRAME_TIME && result.is_ok() {
+                    self.frame_time -= FRAME_TIME;
+                    result = self.run_frame();
+                    self.rewind.capture(&self.system);
+                }
+                result
+            } else {
+                self.run_for(elapsed)
+            };
+
+            if let Err(error) = result {
+                eprintln!("Emulation halted: {}", error);
+            }
+        }
+
+        if let Some(playback) = &self.playback {
+            if playback.finished(&self.system) {
+                println!("Playback finished at frame {}", self.system.frame_count());
+                self.playback = None;
+            }
+        }
+
+        self.update_sound(ctx)
+    }
+
+    // Keep drawing in window pixels as the window is resized
+    fn resize_event(&mut self, ctx: &mut Context, width: f32, height: f32) {
+        if let Err(error) =
+            graphics::set_screen_coordinates(ctx, Rect::new(0.0, 0.0, width, height))
+        {
+            eprintln!("Couldn't resize the screen: {}", error);
+        }
+    }
+
+    fn quit_event(&mut self, _ctx: &mut Context) -> bool {
+        self.finish_recording();
+        self.finish_trace();
+        false
     }
 
     fn draw(&mut self, ctx: &mut Context) -> GameResult {
         graphics::clear(ctx, [0.0, 0.0, 0.0, 0.0].into());
 
-        let mut image = self.system.image_from_display(ctx)?;
+        let rgba = self.renderer.render(&self.system);
+        let rgba = match &mut self.phosphor {
+            Some(phosphor) => phosphor.update(&self.system, rgba, timer::delta(ctx)),
+            None => rgba,
+        };
+        let mut image = Image::from_rgba8(
+            ctx,
+            self.system.display_width() as u16,
+            self.system.display_height() as u16,
+            rgba,
+        )?;
         image.set_filter(FilterMode::Nearest);
-        graphics::draw(ctx, &image, (self.origin,))?;
+
+        let (origin, scale) = self.screen_layout(ctx);
+        let params = DrawParam::new()
+            .dest(origin)
+            .scale(na::Vector2::new(scale, scale));
+        graphics::draw(ctx, &image, params)?;
+
+        if self.overlay {
+            let window = graphics::screen_coordinates(ctx);
+            let dest = na::Point2::new(window.w - overlay::WIDTH + 8.0, 8.0);
+            overlay::draw(ctx, &self.system, dest)?;
+        }
 
         graphics::present(ctx)
     }
 
     fn key_down_event(
         &mut self,
-        _ctx: &mut Context,
+        ctx: &mut Context,
         keycode: event::KeyCode,
-        _keymods: event::KeyMods,
-        _repeat: bool,
+        keymods: event::KeyMods,
+        repeat: bool,
     ) {
+        // F1-F4 save to a slot, and with Shift held load from it
+        let slot = match keycode {
+            event::KeyCode::F1 => Some(1),
+            event::KeyCode::F2 => Some(2),
+            event::KeyCode::F3 => Some(3),
+            event::KeyCode::F4 => Some(4),
+            _ => None,
+        };
+
+        if let Some(slot) = slot {
+            if !repeat {
+                if keymods.contains(event::KeyMods::SHIFT) && self.movie_active() {
+                    eprintln!("States can't be loaded while a movie is recording or playing");
+                } else if keymods.contains(event::KeyMods::SHIFT) {
+                    self.load_state(slot);
+                } else {
+                    self.save_state(slot);
+                }
+            }
+            return;
+        }
+
         match keycode {
-            event::KeyCode::Space => {
-                if self.debug {
-                    self.step = true;
+            // In the debugger Space runs one frame and F5 breaks into a running program
+            event::KeyCode::Space | event::KeyCode::F5 => {
+                if let Some(debugger) = &mut self.debugger {
+                    let command = if keycode == event::KeyCode::Space {
+                        "frame"
+                    } else {
+                        "pause"
+                    };
+
+                    println!("{}", command);
+                    print!("{}", debugger.command(&mut self.system, command));
+                    prompt();
+                }
+            }
+            // Switching theme replaces any colours given on the command line
+            event::KeyCode::P if !repeat => {
+                self.theme = (self.theme + 1) % palette::THEMES.len();
+                let name = palette::THEMES[self.theme];
+
+                self.renderer.set_palette(Palette::from_name(name).unwrap());
+                println!("Theme: {}", name);
+            }
+            event::KeyCode::M if !repeat => {
+                self.muted = !self.muted;
+                println!("Sound {}", if self.muted { "muted" } else { "on" });
+            }
+            // F12 saves a screenshot at the size it's shown, and Shift+F12 at native size
+            event::KeyCode::F12 if !repeat => {
+                let scale = if keymods.contains(event::KeyMods::SHIFT) {
+                    1
+                } else {
+                    let (_, scale) = self.screen_layout(ctx);
+                    scale as usize
+                };
+                self.save_screenshot(scale);
+            }
+            event::KeyCode::F11 if !repeat => {
+                if let Err(error) = self.toggle_fullscreen(ctx) {
+                    eprintln!("Couldn't switch fullscreen: {}", error);
+                }
+            }
+            event::KeyCode::Tab if !repeat => {
+                if let Err(error) = self.set_overlay(ctx, !self.overlay) {
+                    eprintln!("Couldn't resize the window: {}", error);
                 }
             }
+            event::KeyCode::Back => {
+                self.rewinding = !self.movie_active();
+            }
             event::KeyCode::Key1 => {
-                self.system.press_key(1);
+                self.set_key(1, true);
             }
             event::KeyCode::Key2 => {
-                self.system.press_key(2);
+                self.set_key(2, true);
             }
             event::KeyCode::Key3 => {
-                self.system.press_key(3);
+                self.set_key(3, true);
             }
             event::KeyCode::Q => {
-                self.system.press_key(4);
+                self.set_key(4, true);
             }
             event::KeyCode::W => {
-                self.system.press_key(5);
+                self.set_key(5, true);
             }
             event::KeyCode::E => {
-                self.system.press_key(6);
+                self.set_key(6, true);
             }
             event::KeyCode::A => {
-                self.system.press_key(7);
+                self.set_key(7, true);
             }
             event::KeyCode::S => {
-                self.system.press_key(8);
+                self.set_key(8, true);
             }
             event::KeyCode::D => {
-                self.system.press_key(9);
+                self.set_key(9, true);
             }
             event::KeyCode::Z => {
-                self.system.press_key(10);
+                self.set_key(10, true);
             }
             event::KeyCode::C => {
-                self.system.press_key(11);
+                self.set_key(11, true);
             }
             event::KeyCode::Key4 => {
-                self.system.press_key(12);
+                self.set_key(12, true);
             }
             event::KeyCode::R => {
-                self.system.press_key(13);
+                self.set_key(13, true);
             }
             event::KeyCode::F => {
-                self.system.press_key(14);
+                self.set_key(14, true);
             }
             event::KeyCode::V => {
-                self.system.press_key(15);
+                self.set_key(15, true);
             }
             event::KeyCode::X => {
-                self.system.press_key(0);
+                self.set_key(0, true);
             }
             _ => {}
         }
@@ -148,58 +1053,76 @@ impl event::EventHandler for MainState {
 
     fn key_up_event(
         &mut self,
-        _ctx: &mut Context,
+        ctx: &mut Context,
         keycode: event::KeyCode,
         _keymods: event::KeyMods,
     ) {
         match keycode {
+            event::KeyCode::Back => {
+                /* Restored states have whatever keys were held back then, so match them
+                 * to the keyboard again. Nothing is rewound while a movie is active, so
+                 * this never needs recording. */
+                if self.rewinding {
+                    for (key, &keycode) in KEYPAD.iter().enumerate() {
+                        if keyboard::is_key_pressed(ctx, keycode) {
+                            self.system.press_key(key);
+                        } else {
+                            self.system.unpress_key(key);
+                        }
+                    }
+                }
+
+                self.rewinding = false;
+                // The next state is captured a whole frame from the one rewound to
+                self.rewind_time = Duration::from_secs(0);
+            }
             event::KeyCode::Key1 => {
-                self.system.unpress_key(1);
+                self.set_key(1, false);
             }
             event::KeyCode::Key2 => {
-                self.system.unpress_key(2);
+                self.set_key(2, false);
             }
             event::KeyCode::Key3 => {
-                self.system.unpress_key(3);
+                self.set_key(3, false);
             }
             event::KeyCode::Q => {
-                self.system.unpress_key(4);
+                self.set_key(4, false);
             }
             event::KeyCode::W => {
-                self.system.unpress_key(5);
+                self.set_key(5, false);
             }
             event::KeyCode::E => {
-                self.system.unpress_key(6);
+                self.set_key(6, false);
             }
             event::KeyCode::A => {
-                self.system.unpress_key(7);
+                self.set_key(7, false);
             }
             event::KeyCode::S => {
-                self.system.unpress_key(8);
+                self.set_key(8, false);
             }
             event::KeyCode::D => {
-                self.system.unpress_key(9);
+                self.set_key(9, false);
             }
             event::KeyCode::Z => {
-                self.system.unpress_key(10);
+                self.set_key(10, false);
             }
             event::KeyCode::C => {
-                self.system.unpress_key(11);
+                self.set_key(11, false);
             }
             event::KeyCode::Key4 => {
-                self.system.unpress_key(12);
+                self.set_key(12, false);
             }
             event::KeyCode::R => {
-                self.system.unpress_key(13);
+                self.set_key(13, false);
             }
             event::KeyCode::F => {
-                self.system.unpress_key(14);
+                self.set_key(14, false);
             }
             event::KeyCode::V => {
-                self.system.unpress_key(15);
+                self.set_key(15, false);
             }
             event::KeyCode::X => {
-                self.system.unpress_key(0);
+                self.set_key(0, false);
             }
             _ => {}
         }
@@ -0,0 +1,100 @@
+use chip8_core::{Chip8, Instruction};
+use ggez::graphics::{self, DrawParam, Font, Text};
+use ggez::nalgebra as na;
+use ggez::{Context, GameResult};
+use std::fmt::Write;
+
+/* The debug overlay, drawn beside the game screen so the machine can be watched while
+ * stepping through a program. It shows the registers and timers, the call stack, the
+ * code at the program counter and which keys are held. */
+
+// Space the overlay needs, in window pixels
+pub const WIDTH: f32 = 230.0;
+pub const HEIGHT: f32 = 400.0;
+
+const FONT_SIZE: f32 = 12.0;
+
+// Instructions listed, starting with the one about to run
+const INSTRUCTIONS: usize = 6;
+
+// The keypad as laid out on the COSMAC VIP
+const KEYPAD: [[usize; 4]; 4] = [[1, 2, 3, 12], [4, 5, 6, 13], [7, 8, 9, 14], [10, 0, 11, 15]];
+
+pub fn draw(ctx: &mut Context, system: &Chip8, dest: na::Point2<f32>) -> GameResult {
+    let text = Text::new((describe(system), Font::default(), FONT_SIZE));
+    graphics::draw(ctx, &text, DrawParam::new().dest(dest))
+}
+
+fn describe(system: &Chip8) -> String {
+    let mut text = String::new();
+
+    writeln!(
+        text,
+        "PC {:#05X}  I {:#05X}  SP {}",
+        system.pc(),
+        system.index(),
+        system.stack().len()
+    )
+    .unwrap();
+    writeln!(
+        text,
+        "DT {}  ST {}",
+        system.delay_timer(),
+        system.sound_timer()
+    )
+    .unwrap();
+
+    for row in 0..4 {
+        let registers: Vec<String> = (0..4)
+            .map(|i| row * 4 + i)
+            .map(|x| format!("V{:X} {:02X}", x, system.register(x)))
+            .collect();
+        writeln!(text, "{}", registers.join("  ")).unwrap();
+    }
+
+    // Innermost call first, four return addresses to a line
+    text.push_str("\nStack\n");
+    if system.stack().is_empty() {
+        text.push_str("(empty)\n");
+    }
+    let stack: Vec<String> = system
+        .stack()
+        .iter()
+        .rev()
+        .map(|address| format!("{:#05X}", address))
+        .collect();
+    for line in stack.chunks(4) {
+        writeln!(text, "{}", line.join("  ")).unwrap();
+    }
+
+    text.push_str("\nCode\n");
+    let mut address = system.pc();
+    for i in 0..INSTRUCTIONS {
+        let instruction = match Instruction::fetch(system.memory(), address) {
+            Some(instruction) => instruction,
+            None => break,
+        };
+
+        let current = if i == 0 { '>' } else { ' ' };
+        writeln!(text, "{} {:#05X}: {}", current, address, instruction).unwrap();
+        address += instruction.size();
+    }
+
+    // Held keys are shown in brackets
+    text.push_str("\nKeys\n");
+    for row in KEYPAD.iter() {
+        let keys: Vec<String> = row
+            .iter()
+            .map(|&key| {
+                if system.key_pressed(key) {
+                    format!("[{:X}]", key)
+                } else {
+                    format!(" {:X} ", key)
+                }
+            })
+            .collect();
+        writeln!(text, "{}", keys.join(" ")).unwrap();
+    }
+
+    text
+}
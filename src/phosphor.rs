@@ -0,0 +1,68 @@
+use chip8_core::Chip8;
+use std::time::Duration;
+
+/* Fades pixels out gradually after they're erased, like the glow of a CRT's phosphor.
+ * Games move sprites by erasing and redrawing them, so they're often missing from the
+ * frame that happens to be drawn, which flickers. With persistence the erased sprite is
+ * still glowing until it's redrawn.
+ *
+ * This only changes what's drawn: the machine's display is left alone. Pixels that
+ * are lit show in full straight away, and erased pixels fade towards their new colour
+ * over the fade time, whatever the palette. */
+pub struct Phosphor {
+    // How long an erased pixel takes to fade to 5% of its old brightness
+    fade: Duration,
+    resolution: (usize, usize),
+    // The colour of every pixel as currently drawn, from 0 to 255
+    glow: Vec<f32>,
+    rgba: Vec<u8>,
+}
+
+// The brightness left after the fade time
+const FADED: f32 = 0.05;
+
+impl Phosphor {
+    pub fn new(fade: Duration) -> Phosphor {
+        Phosphor {
+            fade,
+            resolution: (0, 0),
+            glow: Vec::new(),
+            rgba: Vec::new(),
+        }
+    }
+
+    /* Move on by elapsed time since the last call, returning the rgba buffer to draw
+     * instead of target, the machine's screen as rendered. */
+    pub fn update(&mut self, system: &Chip8, target: &[u8], elapsed: Duration) -> &[u8] {
+        let resolution = (system.display_width(), system.display_height());
+
+        // There's nothing to fade from after switching resolution
+        if resolution != self.resolution {
+            self.resolution = resolution;
+            self.glow = target.iter().map(|&value| f32::from(value)).collect();
+            self.rgba = target.to_vec();
+            return &self.rgba;
+        }
+
+        let remaining = if self.fade.as_secs_f32() > 0.0 {
+            FADED.powf(elapsed.as_secs_f32() / self.fade.as_secs_f32())
+        } else {
+            0.0
+        };
+        let (width, _) = resolution;
+
+        for (i, (glow, &value)) in self.glow.iter_mut().zip(target.iter()).enumerate() {
+            let pixel = i / 4;
+            let value = f32::from(value);
+
+            *glow = if system.pixel(pixel % width, pixel / width) != 0 {
+                value
+            } else {
+                value + (*glow - value) * remaining
+            };
+            self.rgba[i] = glow.round() as u8;
+        }
+
+        &self.rgba
+    }
+}